@@ -0,0 +1,4 @@
+// миграции встраиваются в бинарь через sqlx::migrate!, пересобираем при их изменении
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,76 @@
+-- реестр шардов, create_shard создает таблицу шарда нужной формы и регистрирует ее.
+-- функция идемпотентна: следующие миграции пересоздают ее с новыми колонками
+-- и вызывают для всех шардов из реестра
+
+CREATE TABLE IF NOT EXISTS shards
+(
+    name text PRIMARY KEY,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE OR REPLACE FUNCTION create_shard(shard text) RETURNS void AS $$
+BEGIN
+    EXECUTE format('CREATE TABLE IF NOT EXISTS %I
+    (
+        nm_id int8 PRIMARY KEY,
+        old_pics_count int2 NOT NULL
+    )', shard);
+
+    EXECUTE format('ALTER TABLE %I
+        ADD COLUMN IF NOT EXISTS new_pics_count int2 NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS in_process boolean NOT NULL DEFAULT false,
+        ADD COLUMN IF NOT EXISTS is_finished boolean NOT NULL DEFAULT false,
+        ADD COLUMN IF NOT EXISTS retries int8 NOT NULL DEFAULT 0,
+        ADD COLUMN IF NOT EXISTS good_links text NOT NULL DEFAULT '''',
+        ADD COLUMN IF NOT EXISTS worker_id text NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS claimed_at timestamptz NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS lease_until timestamptz NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS error text NOT NULL DEFAULT '''',
+        ADD COLUMN IF NOT EXISTS error_kind text NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS error_status int2 NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS error_url text NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS next_attempt_at timestamptz NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS failures jsonb NOT NULL DEFAULT ''[]''', shard);
+
+    INSERT INTO shards (name) VALUES (shard) ON CONFLICT DO NOTHING;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TABLE IF NOT EXISTS dead_letter
+(
+    shard text NOT NULL,
+    nm_id int8 NOT NULL,
+    old_pics_count int2 NOT NULL,
+    retries int8 NOT NULL,
+    error text NOT NULL DEFAULT '',
+    error_kind text NULL DEFAULT NULL,
+    error_status int2 NULL DEFAULT NULL,
+    error_url text NULL DEFAULT NULL,
+    history jsonb NOT NULL DEFAULT '[]',
+    worker_id text NULL DEFAULT NULL,
+    dead_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (shard, nm_id)
+);
+
+-- shard_1..shard_10 раньше создавались руками, существующие таблицы дополняются недостающими колонками
+
+SELECT create_shard('shard_' || i) FROM generate_series(1, 10) AS i;
+
+-- строки, исчерпавшие ретраи до появления dead_letter
+
+DO $$
+DECLARE
+    shard text;
+BEGIN
+    FOR shard IN SELECT name FROM shards LOOP
+        EXECUTE format('WITH dead AS (
+            DELETE FROM %1$I WHERE retries >= 3 RETURNING *
+        )
+        INSERT INTO dead_letter
+            (shard, nm_id, old_pics_count, retries, error, error_kind, error_status, error_url, history, worker_id)
+        SELECT %2$L, nm_id, old_pics_count, retries, error, error_kind, error_status, error_url, failures, worker_id
+        FROM dead
+        ON CONFLICT (shard, nm_id) DO NOTHING', shard, shard);
+    END LOOP;
+END
+$$;
@@ -0,0 +1,16 @@
+-- append-only история попыток: одна строка на каждую проверку номенклатуры воркером
+
+CREATE TABLE IF NOT EXISTS attempts
+(
+    id bigserial PRIMARY KEY,
+    shard text NOT NULL,
+    nm_id int8 NOT NULL,
+    worker_id text NOT NULL,
+    started_at timestamptz NOT NULL,
+    finished_at timestamptz NOT NULL,
+    statuses int2[] NOT NULL DEFAULT '{}',
+    outcome text NOT NULL,
+    error text NULL DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS attempts_shard_nm_id_idx ON attempts (shard, nm_id, started_at);
@@ -0,0 +1,38 @@
+-- токен захвата джобы - worker_id + claim_generation; pull увеличивает generation,
+-- поэтому результат устаревшего захвата не совпадет с текущим и будет отброшен
+
+CREATE OR REPLACE FUNCTION create_shard(shard text) RETURNS void AS $$
+BEGIN
+    EXECUTE format('CREATE TABLE IF NOT EXISTS %I
+    (
+        nm_id int8 PRIMARY KEY,
+        old_pics_count int2 NOT NULL
+    )', shard);
+
+    EXECUTE format('ALTER TABLE %I
+        ADD COLUMN IF NOT EXISTS new_pics_count int2 NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS in_process boolean NOT NULL DEFAULT false,
+        ADD COLUMN IF NOT EXISTS is_finished boolean NOT NULL DEFAULT false,
+        ADD COLUMN IF NOT EXISTS retries int8 NOT NULL DEFAULT 0,
+        ADD COLUMN IF NOT EXISTS good_links text NOT NULL DEFAULT '''',
+        ADD COLUMN IF NOT EXISTS worker_id text NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS claimed_at timestamptz NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS lease_until timestamptz NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS error text NOT NULL DEFAULT '''',
+        ADD COLUMN IF NOT EXISTS error_kind text NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS error_status int2 NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS error_url text NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS next_attempt_at timestamptz NULL DEFAULT NULL,
+        ADD COLUMN IF NOT EXISTS failures jsonb NOT NULL DEFAULT ''[]'',
+        ADD COLUMN IF NOT EXISTS claim_generation int8 NOT NULL DEFAULT 0', shard);
+
+    EXECUTE format('DROP TRIGGER IF EXISTS notify_shard_jobs ON %I', shard);
+    EXECUTE format('CREATE TRIGGER notify_shard_jobs
+        AFTER INSERT ON %I
+        FOR EACH STATEMENT EXECUTE FUNCTION notify_shard_jobs()', shard);
+
+    INSERT INTO shards (name) VALUES (shard) ON CONFLICT DO NOTHING;
+END
+$$ LANGUAGE plpgsql;
+
+SELECT create_shard(name) FROM shards;
@@ -0,0 +1,17 @@
+-- реестр запущенных process: регистрация при старте, heartbeat со счетчиками,
+-- удаление при штатной остановке; строки с устаревшим heartbeat - упавшие реплики
+
+CREATE TABLE IF NOT EXISTS workers
+(
+    worker_id text PRIMARY KEY,
+    hostname text NOT NULL,
+    shards text[] NOT NULL DEFAULT '{}',
+    version text NOT NULL,
+    started_at timestamptz NOT NULL,
+    heartbeat_at timestamptz NOT NULL DEFAULT now(),
+    finished int8 NOT NULL DEFAULT 0,
+    failed int8 NOT NULL DEFAULT 0,
+    lost_claims int8 NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS workers_heartbeat_at_idx ON workers (heartbeat_at);
@@ -0,0 +1,82 @@
+-- шарды становятся секциями одной таблицы nomenclatures, разбитой по списку значений shard.
+-- секция называется так же, как шард, поэтому запросы к шарду по-прежнему идут прямо в нее;
+-- новые колонки добавляются одним ALTER TABLE nomenclatures, а шарды - миграцией
+-- с SELECT create_shard(...) / drop_shard(...)
+
+-- таблица из старого init.sql, кодом не используется
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM pg_class
+        WHERE relname = 'nomenclatures' AND relnamespace = 'public'::regnamespace AND relkind = 'r'
+    ) THEN
+        ALTER TABLE nomenclatures RENAME TO nomenclatures_legacy;
+    END IF;
+END
+$$;
+
+CREATE TABLE IF NOT EXISTS nomenclatures
+(
+    shard text NOT NULL,
+    nm_id int8 NOT NULL,
+    old_pics_count int2 NOT NULL,
+    new_pics_count int2 NULL DEFAULT NULL,
+    in_process boolean NOT NULL DEFAULT false,
+    is_finished boolean NOT NULL DEFAULT false,
+    retries int8 NOT NULL DEFAULT 0,
+    good_links text NOT NULL DEFAULT '',
+    worker_id text NULL DEFAULT NULL,
+    claimed_at timestamptz NULL DEFAULT NULL,
+    lease_until timestamptz NULL DEFAULT NULL,
+    error text NOT NULL DEFAULT '',
+    error_kind text NULL DEFAULT NULL,
+    error_status int2 NULL DEFAULT NULL,
+    error_url text NULL DEFAULT NULL,
+    next_attempt_at timestamptz NULL DEFAULT NULL,
+    failures jsonb NOT NULL DEFAULT '[]',
+    claim_generation int8 NOT NULL DEFAULT 0,
+    PRIMARY KEY (shard, nm_id)
+) PARTITION BY LIST (shard);
+
+CREATE OR REPLACE FUNCTION create_shard(shard text) RETURNS void AS $$
+DECLARE
+    pkey text;
+BEGIN
+    IF to_regclass(quote_ident(shard)) IS NULL THEN
+        EXECUTE format('CREATE TABLE %I PARTITION OF nomenclatures FOR VALUES IN (%L)', shard, shard);
+    ELSIF NOT EXISTS (SELECT 1 FROM pg_inherits WHERE inhrelid = to_regclass(quote_ident(shard))) THEN
+        -- таблица, созданная до секционирования: колонки совпадают с nomenclatures после 0004,
+        -- не хватает только shard; первичный ключ (nm_id) заменяется уникальным индексом,
+        -- ключ (shard, nm_id) секция получит от nomenclatures
+        EXECUTE format('ALTER TABLE %I ADD COLUMN IF NOT EXISTS shard text NOT NULL DEFAULT %L', shard, shard);
+        EXECUTE format('CREATE UNIQUE INDEX IF NOT EXISTS %I ON %I (nm_id)', shard || '_nm_id_key', shard);
+        SELECT conname INTO pkey FROM pg_constraint
+        WHERE conrelid = to_regclass(quote_ident(shard)) AND contype = 'p';
+        IF pkey IS NOT NULL THEN
+            EXECUTE format('ALTER TABLE %I DROP CONSTRAINT %I', shard, pkey);
+        END IF;
+        EXECUTE format('ALTER TABLE nomenclatures ATTACH PARTITION %I FOR VALUES IN (%L)', shard, shard);
+    END IF;
+
+    -- вставки идут прямо в секцию без колонки shard и с on conflict (nm_id)
+    EXECUTE format('ALTER TABLE %I ALTER COLUMN shard SET DEFAULT %L', shard, shard);
+    EXECUTE format('CREATE UNIQUE INDEX IF NOT EXISTS %I ON %I (nm_id)', shard || '_nm_id_key', shard);
+
+    EXECUTE format('DROP TRIGGER IF EXISTS notify_shard_jobs ON %I', shard);
+    EXECUTE format('CREATE TRIGGER notify_shard_jobs
+        AFTER INSERT ON %I
+        FOR EACH STATEMENT EXECUTE FUNCTION notify_shard_jobs()', shard);
+
+    INSERT INTO shards (name) VALUES (shard) ON CONFLICT DO NOTHING;
+END
+$$ LANGUAGE plpgsql;
+
+-- удаляет секцию шарда вместе с джобами; dead_letter и attempts сохраняются
+CREATE OR REPLACE FUNCTION drop_shard(shard text) RETURNS void AS $$
+BEGIN
+    EXECUTE format('DROP TABLE IF EXISTS %I', shard);
+    DELETE FROM shards WHERE name = shard;
+END
+$$ LANGUAGE plpgsql;
+
+SELECT create_shard(name) FROM shards;
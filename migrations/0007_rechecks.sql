@@ -0,0 +1,24 @@
+-- перепроверка готовых номенклатур: finished_at - конец последней успешной проверки,
+-- rechecks - сколько раз номенклатуру снова брали в работу после готовности.
+-- готовым до появления колонки finished_at ставится время миграции,
+-- иначе на перепроверку разом ушла бы вся таблица
+
+ALTER TABLE nomenclatures
+    ADD COLUMN IF NOT EXISTS finished_at timestamptz NULL DEFAULT NULL,
+    ADD COLUMN IF NOT EXISTS rechecks int4 NOT NULL DEFAULT 0;
+
+UPDATE nomenclatures SET finished_at = now() WHERE is_finished AND finished_at IS NULL;
+
+-- append-only результаты перепроверок: было и стало картинок
+CREATE TABLE IF NOT EXISTS rechecks
+(
+    id bigserial PRIMARY KEY,
+    shard text NOT NULL,
+    nm_id int8 NOT NULL,
+    checked_at timestamptz NOT NULL DEFAULT now(),
+    previous_pics_count int2 NULL DEFAULT NULL,
+    new_pics_count int2 NOT NULL,
+    good_links text NOT NULL DEFAULT ''
+);
+
+CREATE INDEX IF NOT EXISTS rechecks_shard_nm_id_idx ON rechecks (shard, nm_id, checked_at);
@@ -0,0 +1,9 @@
+-- номера картинок, которые нашлись при последней проверке, включая те, что после пропуска;
+-- new_pics_count по-прежнему длина непрерывного ряда с первой картинки.
+-- у уже проверенных известен только непрерывный ряд
+
+ALTER TABLE nomenclatures ADD COLUMN IF NOT EXISTS pics int2[] NOT NULL DEFAULT '{}';
+
+UPDATE nomenclatures
+SET pics = array(select generate_series(1, new_pics_count)::int2)
+WHERE is_finished AND new_pics_count > 0 AND pics = '{}';
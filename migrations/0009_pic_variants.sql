@@ -0,0 +1,12 @@
+-- какие размеры и форматы нашлись для каждой картинки: {"1": ["big.jpg", "big.webp"], "3": ["big.jpg"]};
+-- pics и new_pics_count считаются по основной паре - первым размеру и формату из настроек process.
+-- у уже проверенных известна только big.jpg
+
+ALTER TABLE nomenclatures ADD COLUMN IF NOT EXISTS pic_variants jsonb NOT NULL DEFAULT '{}';
+
+UPDATE nomenclatures
+SET pic_variants = (
+    select jsonb_object_agg(idx, jsonb_build_array('big.jpg'))
+    from unnest(pics) as idx
+)
+WHERE is_finished AND cardinality(pics) > 0 AND pic_variants = '{}';
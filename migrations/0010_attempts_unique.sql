@@ -0,0 +1,11 @@
+-- commit_batch повторяет транзакцию, COMMIT которой мог пройти: повторная вставка
+-- той же попытки пропускается по (shard, nm_id, worker_id, started_at)
+
+DELETE FROM attempts a
+USING attempts b
+WHERE a.id > b.id
+    AND a.shard = b.shard AND a.nm_id = b.nm_id
+    AND a.worker_id = b.worker_id AND a.started_at = b.started_at;
+
+CREATE UNIQUE INDEX IF NOT EXISTS attempts_shard_nm_id_worker_id_started_at_key
+    ON attempts (shard, nm_id, worker_id, started_at);
@@ -0,0 +1,36 @@
+-- ad-hoc запросы для ручной проверки шардов, схема - в migrations/
+-- прогресс шарда целиком считает Queue::stats, process пишет его в лог раз в STATS_INTERVAL_SECS
+
+-- исчерпавшие ретраи джобы уходят из шарда в dead_letter, поэтому считаются отдельно
+with f as (
+	select count(*) as "done" from public.shard_2 s
+	where s.is_finished = true
+)
+, d as (
+	select count(*) as "dead" from public.dead_letter
+	where shard = 'shard_2'
+)
+, t as (
+	select count(*) as "total" from public.shard_2
+)
+select f."done", d."dead", t."total" + d."dead" as "total" from f
+cross join d
+cross join t
+
+-- сброс джоб (зависшие, без картинок, упавшие, регрессии, по nm_id) - Queue::requeue,
+-- Queue::reset_retries и Queue::clear_results с JobFilter
+
+-- история проверок номенклатуры (то же отдает Queue::attempts)
+select * from attempts where shard = 'shard_1' and nm_id = 91249210 order by started_at, id;
+
+-- живые и упавшие реплики process (то же отдает Queue::workers)
+select worker_id, hostname, shards, version, started_at, now() - heartbeat_at as silent_for,
+    finished, failed, lost_claims
+from workers
+order by heartbeat_at;
+
+-- номенклатуры, потерявшие картинки при перепроверке (результаты одной отдает Queue::rechecks)
+select shard, nm_id, checked_at, previous_pics_count, new_pics_count
+from rechecks
+where new_pics_count < previous_pics_count
+order by checked_at desc;
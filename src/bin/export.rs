@@ -0,0 +1,227 @@
+use std::{
+    env::var,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use {
+    futures::{future::try_join_all, TryStreamExt},
+    serde::Serialize,
+    tokio::runtime::Builder,
+    tracing::{info, Level},
+};
+
+use {
+    cd1574 as q,
+    q::store::{
+        export::{export_query, ExportRow, ExportStatus},
+        shard::Shard,
+        sqlx_queue::{self, Queue},
+    },
+};
+
+const LOG_LEVEL: Level = Level::INFO;
+
+const POSTGRES_DB: &str = "POSTGRES_DB";
+const POSTGRES_USER: &str = "POSTGRES_USER";
+const POSTGRES_PASSWORD: &str = "POSTGRES_PASSWORD";
+const POSTGRES_HOST: &str = "POSTGRES_HOST";
+const POSTGRES_PORT: &str = "POSTGRES_PORT";
+
+/// путь к файлу выгрузки; "-" - stdout
+const EXPORT_FILE: &str = "EXPORT_FILE";
+/// csv или ndjson
+const EXPORT_FORMAT: &str = "EXPORT_FORMAT";
+/// finished, failed, regressed или gaps
+const EXPORT_STATUS: &str = "EXPORT_STATUS";
+/// true - одна строка на каждую ссылку из good_links
+const EXPORT_EXPAND_LINKS: &str = "EXPORT_EXPAND_LINKS";
+/// список шардов через запятую, по умолчанию все шарды из реестра
+const SHARDS: &str = "SHARDS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, Serialize)]
+struct Record<'a> {
+    shard: &'a str,
+    nm_id: i64,
+    old_pics_count: i16,
+    new_pics_count: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    good_links: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<&'a str>,
+    missing_pics: &'a str,
+    error: &'a str,
+}
+
+enum Output {
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Ndjson(Box<dyn Write>),
+}
+
+impl Output {
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        match self {
+            Output::Csv(w) => w.serialize(record)?,
+            Output::Ndjson(w) => {
+                serde_json::to_writer(&mut *w, record)?;
+                w.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match self {
+            Output::Csv(w) => w.flush()?,
+            Output::Ndjson(w) => w.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let rt = Builder::new_multi_thread().enable_all().build()?;
+    let _guard = rt.enter();
+    init_tracing();
+
+    let q_user = var(POSTGRES_USER).unwrap_or_else(|_| String::from("content"));
+    let q_password = var(POSTGRES_PASSWORD).unwrap_or_else(|_| String::from("1231"));
+    let q_host = var(POSTGRES_HOST).unwrap_or_else(|_| String::from("localhost"));
+    let q_port = var(POSTGRES_PORT).unwrap_or_else(|_| String::from("5433"));
+    let q_database = var(POSTGRES_DB).unwrap_or_else(|_| String::from("content"));
+
+    let file = var(EXPORT_FILE).unwrap_or_else(|_| String::from("-"));
+    let format = match var(EXPORT_FORMAT)
+        .unwrap_or_else(|_| String::from("csv"))
+        .as_str()
+    {
+        "csv" => Format::Csv,
+        "ndjson" | "jsonl" => Format::Ndjson,
+        other => anyhow::bail!("unknown export format [{other}], expected csv|ndjson"),
+    };
+    let status = var(EXPORT_STATUS)
+        .unwrap_or_else(|_| String::from("finished"))
+        .parse::<ExportStatus>()?;
+    let expand_links = var(EXPORT_EXPAND_LINKS)
+        .unwrap_or_else(|_| String::from("false"))
+        .parse::<bool>()?;
+
+    // логи идут в stderr, stdout может быть занят выгрузкой
+    info!("host=[{q_host}]");
+    info!("database=[{q_database}]");
+    info!("file=[{file}] format=[{format:?}] status=[{status}] expand_links=[{expand_links}]");
+
+    let queue = rt.block_on(async {
+        sqlx_queue::SqlxPool::new(q_host, q_port, q_user, q_password, q_database).await
+    })?;
+
+    let shards = match var(SHARDS) {
+        Ok(shards) => rt.block_on(try_join_all(
+            shards
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|name| queue.shard(name)),
+        ))?,
+        Err(_) => rt.block_on(queue.shards())?,
+    };
+    info!(
+        "shards=[{}]",
+        shards
+            .iter()
+            .map(Shard::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+
+    let writer: Box<dyn Write> = if file == "-" {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        Box::new(BufWriter::new(File::create(&file)?))
+    };
+    let mut output = match format {
+        Format::Csv => Output::Csv(Box::new(csv::Writer::from_writer(writer))),
+        Format::Ndjson => Output::Ndjson(writer),
+    };
+
+    rt.block_on(async {
+        for shard in &shards {
+            let query = export_query(shard, status);
+            let mut rows = sqlx::query_as::<_, ExportRow>(&query)
+                .bind(shard.as_str())
+                .fetch(&queue.client);
+            let mut exported = 0u64;
+            while let Some(row) = rows.try_next().await? {
+                write_row(&mut output, &row, expand_links)?;
+                exported += 1;
+            }
+            info!("{exported} nomenclatures were exported from {shard}");
+        }
+        anyhow::Ok(())
+    })?;
+    output.flush()?;
+
+    Ok(())
+}
+
+fn write_row(output: &mut Output, row: &ExportRow, expand_links: bool) -> anyhow::Result<()> {
+    let record = Record {
+        shard: &row.shard,
+        nm_id: row.nm_id,
+        old_pics_count: row.old_pics_count,
+        new_pics_count: row.new_pics_count,
+        good_links: None,
+        link: None,
+        missing_pics: &row.missing_pics,
+        error: &row.error,
+    };
+    if !expand_links {
+        return output.write(&Record {
+            good_links: Some(&row.good_links),
+            ..record
+        });
+    }
+    let mut links = row
+        .good_links
+        .split(';')
+        .filter(|l| !l.is_empty())
+        .peekable();
+    // номенклатура без найденных картинок не пропадает из выгрузки, ссылка пустая
+    if links.peek().is_none() {
+        return output.write(&Record {
+            link: Some(""),
+            ..record
+        });
+    }
+    for link in links {
+        output.write(&Record {
+            link: Some(link),
+            ..record
+        })?;
+    }
+    Ok(())
+}
+
+fn init_tracing() {
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::{filter, fmt, registry};
+
+    let targets = filter::Targets::new().with_target("export", LOG_LEVEL);
+    let fmt = fmt::layer()
+        .with_writer(io::stderr)
+        .with_target(false)
+        .with_file(true)
+        .with_line_number(true);
+
+    if atty::is(atty::Stream::Stderr) {
+        registry().with(fmt).with(targets).init();
+    } else {
+        registry().with(fmt.json()).with(targets).init()
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    env::var,
+    fs::File,
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+use {
+    futures::future::try_join_all,
+    tokio::runtime::Builder,
+    tracing::{error, info, warn, Level},
+};
+
+use {
+    cd1574 as q,
+    q::store::{
+        import::{copy_into_shard, read_rows, shard_index},
+        shard::Shard,
+        sqlx_queue::{self, Queue},
+    },
+};
+
+const LOG_LEVEL: Level = Level::INFO;
+
+const POSTGRES_DB: &str = "POSTGRES_DB";
+const POSTGRES_USER: &str = "POSTGRES_USER";
+const POSTGRES_PASSWORD: &str = "POSTGRES_PASSWORD";
+const POSTGRES_HOST: &str = "POSTGRES_HOST";
+const POSTGRES_PORT: &str = "POSTGRES_PORT";
+
+/// путь к csv c колонками nm_id,old_pics_count; "-" - stdin
+const IMPORT_FILE: &str = "IMPORT_FILE";
+/// список шардов через запятую, по умолчанию все шарды из реестра
+const SHARDS: &str = "SHARDS";
+const CHUNK_SIZE: &str = "CHUNK_SIZE";
+
+fn main() -> anyhow::Result<()> {
+    let rt = Builder::new_multi_thread().enable_all().build()?;
+    let _guard = rt.enter();
+    init_tracing();
+    info!("import.rs started");
+
+    let q_user = var(POSTGRES_USER).unwrap_or_else(|_| String::from("content"));
+    let q_password = var(POSTGRES_PASSWORD).unwrap_or_else(|_| String::from("1231"));
+    let q_host = var(POSTGRES_HOST).unwrap_or_else(|_| String::from("localhost"));
+    let q_port = var(POSTGRES_PORT).unwrap_or_else(|_| String::from("5433"));
+    let q_database = var(POSTGRES_DB).unwrap_or_else(|_| String::from("content"));
+
+    let file = var(IMPORT_FILE).unwrap_or_else(|_| String::from("-"));
+    let chunk_size = var(CHUNK_SIZE)
+        .unwrap_or_else(|_| String::from("100000"))
+        .parse::<usize>()?;
+
+    info!("host=[{q_host}]");
+    info!("database=[{q_database}]");
+    info!("file=[{file}]");
+    info!("chunk_size=[{chunk_size}]");
+
+    let queue = rt.block_on(async {
+        sqlx_queue::SqlxPool::new(q_host, q_port, q_user, q_password, q_database).await
+    })?;
+    rt.block_on(queue.migrate())?;
+
+    let shards = match var(SHARDS) {
+        Ok(shards) => rt.block_on(try_join_all(
+            shards
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|name| queue.shard(name)),
+        ))?,
+        Err(_) => rt.block_on(queue.shards())?,
+    };
+    if shards.is_empty() {
+        anyhow::bail!("no shards to import into");
+    }
+    info!(
+        "shards=[{}]",
+        shards
+            .iter()
+            .map(Shard::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+
+    let input: Box<dyn Read + Send> = if file == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(&file)?)
+    };
+
+    // csv читается синхронно в отдельном потоке, в базу уходят готовые чанки
+    let (chunks_tx, chunks_rx) = flume::bounded(4);
+    let reader = std::thread::spawn(move || read_chunks(input, chunk_size, chunks_tx));
+
+    rt.block_on(async {
+        let started = Instant::now();
+        let (mut read, mut inserted) = (0u64, 0u64);
+        let mut last_report = Instant::now();
+        while let Ok(chunk) = chunks_rx.recv_async().await {
+            let mut by_shard = vec![Vec::new(); shards.len()];
+            for row in chunk {
+                by_shard[shard_index(row.0, shards.len())].push(row);
+                read += 1;
+            }
+            let counts = try_join_all(
+                shards
+                    .iter()
+                    .zip(by_shard.iter())
+                    .map(|(shard, rows)| copy_into_shard(&queue.client, shard, rows)),
+            )
+            .await?;
+            inserted += counts.iter().sum::<u64>();
+
+            if last_report.elapsed() >= Duration::from_secs(5) {
+                last_report = Instant::now();
+                report(read, inserted, started);
+            }
+        }
+        report(read, inserted, started);
+        anyhow::Ok(())
+    })?;
+
+    match reader.join() {
+        Ok(result) => result?,
+        Err(_) => error!("csv reader thread panicked"),
+    }
+
+    info!("import finished");
+    Ok(())
+}
+
+fn report(read: u64, inserted: u64, started: Instant) {
+    let rate = read as f64 / started.elapsed().as_secs_f64().max(f64::EPSILON);
+    info!(
+        "read={read} inserted={inserted} skipped={} rate={rate:.0} rows/s",
+        read - inserted
+    );
+}
+
+fn read_chunks(
+    input: Box<dyn Read + Send>,
+    chunk_size: usize,
+    tx: flume::Sender<Vec<(i64, i16)>>,
+) -> anyhow::Result<()> {
+    let mut chunk = Vec::with_capacity(chunk_size);
+    for row in read_rows(input) {
+        match row {
+            Ok(row) => chunk.push(row),
+            Err(err) => {
+                warn!("{err}");
+                continue;
+            }
+        }
+        if chunk.len() == chunk_size {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size));
+            if tx.send(full).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = tx.send(chunk);
+    }
+    Ok(())
+}
+
+fn init_tracing() {
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::{filter, fmt, registry};
+
+    let targets = filter::Targets::new().with_target("import", LOG_LEVEL);
+    let fmt = fmt::layer()
+        .with_target(false)
+        .with_file(true)
+        .with_line_number(true);
+
+    if atty::is(atty::Stream::Stdout) {
+        registry().with(fmt).with(targets).init();
+    } else {
+        registry().with(fmt.json()).with(targets).init()
+    }
+}
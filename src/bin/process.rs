@@ -1,37 +1,283 @@
-use std::{env::var, time::Duration};
+use std::{
+    collections::HashMap,
+    env::var,
+    fs::File,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use flume::SendError;
 use futures::stream::FuturesUnordered;
 
 use {
-    anyhow::anyhow,
-    futures::prelude::*,
+    chrono::Utc,
+    futures::{
+        future::{join_all, try_join_all},
+        prelude::*,
+    },
     reqwest::{Request, Response},
-    tokio::{runtime::Builder, time::sleep},
+    tokio::{
+        runtime::{Builder, Runtime},
+        signal::unix::{signal, SignalKind},
+        time::sleep,
+    },
     tower::{service_fn, BoxError, Service, ServiceBuilder},
-    tracing::{error, info, trace, Level},
+    tracing::{error, info, trace, warn, Level},
 };
 
 use {
     cd1574 as q,
-    q::store::{models::Nomenclature, sqlx_queue, sqlx_queue::Queue},
+    q::store::{
+        backoff::Backoff,
+        import,
+        memory_queue::InMemoryQueue,
+        models::{Attempt, Batch, Claim, Failure, FailureKind, Nomenclature, Variants, Worker},
+        recheck::RecheckPolicy,
+        shard::Shard,
+        spill::Spill,
+        sqlx_queue,
+        sqlx_queue::Queue,
+    },
 };
 
-const JPG: &str = "jpg";
-static SIZES: &[(&str, bool)] = &[
-    // ("big", true),
-    // ("large", false),
-    // ("c516x688", true),
-    // ("square", true),
-    // ("c324x432", false),
-    // ("c252x336", false),
-    // ("c246x328", true),
-    // ("small", false),
-    // ("tm", false),
-    ("big", false),
-];
-
 const WORKERS: usize = 20;
+
+/// размеры и форматы, которые проверяются для каждой картинки;
+/// первая пара - основная, по ней считаются pics, new_pics_count и good_links
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Matrix {
+    sizes: Vec<String>,
+    formats: Vec<String>,
+}
+
+impl Matrix {
+    /// списки через запятую: big,c516x688,tm и jpg,webp,avif
+    fn new(sizes: &str, formats: &str) -> anyhow::Result<Self> {
+        let parse = |list: &str, what: &str| -> anyhow::Result<Vec<String>> {
+            let items = list
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    if s.chars().all(|c| c.is_ascii_alphanumeric()) {
+                        Ok(s.to_ascii_lowercase())
+                    } else {
+                        Err(anyhow::anyhow!("invalid image {what} [{s}]"))
+                    }
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            anyhow::ensure!(!items.is_empty(), "no image {what} in [{list}]");
+            Ok(items)
+        };
+        Ok(Self {
+            sizes: parse(sizes, "size")?,
+            formats: parse(formats, "format")?,
+        })
+    }
+
+    /// ссылки на картинки pics: номер картинки, вариант размер.формат и url;
+    /// для каждой картинки подряд идут все размеры, внутри размера - все форматы
+    fn links(&self, nm_id: i64, pics: RangeInclusive<i16>) -> Vec<(i16, String, String)> {
+        let bucket = nm_id / 10000 * 10000;
+        let mut links = Vec::new();
+        for i in pics {
+            for size in &self.sizes {
+                for format in &self.formats {
+                    let url = format_url(size, bucket, &nm_id, i, format);
+                    links.push((i, format!("{size}.{format}"), url));
+                }
+            }
+        }
+        links
+    }
+
+    /// ссылка на картинку i в основном варианте
+    fn url(&self, nm_id: i64, i: i16) -> String {
+        let bucket = nm_id / 10000 * 10000;
+        format_url(&self.sizes[0], bucket, &nm_id, i, &self.formats[0])
+    }
+
+    /// все пары размер.формат в порядке настроек, первая - основная
+    fn variants(&self) -> Vec<String> {
+        self.sizes
+            .iter()
+            .flat_map(|size| {
+                self.formats
+                    .iter()
+                    .map(move |format| format!("{size}.{format}"))
+            })
+            .collect()
+    }
+}
+
+impl Default for Matrix {
+    fn default() -> Self {
+        Self {
+            sizes: vec![String::from("big")],
+            formats: vec![String::from("jpg")],
+        }
+    }
+}
+
+/// как проверять, что картинка есть
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Probe {
+    /// HEAD без тела; если хост его отклоняет - Range
+    Head,
+    /// GET первого байта
+    Range,
+    /// GET картинки целиком
+    Get,
+}
+
+impl Probe {
+    fn request(self, url: &str) -> reqwest::Result<Request> {
+        let client = reqwest::Client::new();
+        let builder = match self {
+            Probe::Head => client.head(url),
+            Probe::Range => client.get(url).header(reqwest::header::RANGE, "bytes=0-0"),
+            Probe::Get => client.get(url),
+        };
+        builder.timeout(Duration::from_secs(15)).build()
+    }
+}
+
+impl FromStr for Probe {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "head" => Ok(Probe::Head),
+            "range" => Ok(Probe::Range),
+            "get" => Ok(Probe::Get),
+            _ => anyhow::bail!("unknown probe [{s}], expected head|range|get"),
+        }
+    }
+}
+
+/// способ проверки по хостам. хосты, отклонившие HEAD, запоминаются и дальше
+/// проверяются через Range без лишнего запроса. HEAD c 4xx от хоста, которому
+/// еще нет доверия, перепроверяется через Range: нашлась картинка - хост переходит
+/// на Range, тоже 404 - его ответам на HEAD дальше верим
+#[derive(Debug, Clone)]
+struct Probes {
+    default: Probe,
+    hosts: Arc<Mutex<HashMap<String, Probe>>>,
+}
+
+impl Probes {
+    fn new(default: Probe, hosts: HashMap<String, Probe>) -> Self {
+        Self {
+            default,
+            hosts: Arc::new(Mutex::new(hosts)),
+        }
+    }
+
+    fn get(&self, host: &str) -> Probe {
+        self.lock().get(host).copied().unwrap_or(self.default)
+    }
+
+    fn reject_head(&self, host: &str) {
+        if self.lock().insert(host.to_string(), Probe::Range) != Some(Probe::Range) {
+            warn!("{host} rejects HEAD, falling back to range GET");
+        }
+    }
+
+    /// HEAD хоста проверен GET'ом или задан в PROBE_HOSTS явно
+    fn trusts_head(&self, host: &str) -> bool {
+        self.lock().get(host) == Some(&Probe::Head)
+    }
+
+    fn trust_head(&self, host: &str) {
+        self.lock().entry(host.to_string()).or_insert(Probe::Head);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Probe>> {
+        self.hosts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Default for Probes {
+    fn default() -> Self {
+        Self::new(Probe::Head, HashMap::new())
+    }
+}
+
+/// откуда брать работу, когда в своем шарде пусто
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Steal {
+    /// ждать новых джоб в своем шарде
+    Never,
+    /// забирать джобы из чужого шарда, где их больше всего
+    Busiest,
+}
+
+impl FromStr for Steal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Steal::Never),
+            "busiest" => Ok(Steal::Busiest),
+            _ => anyhow::bail!("unknown steal policy [{s}], expected never|busiest"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    /// шарды процесса; пустой список до run - все шарды из реестра
+    shards: Vec<Shard>,
+    /// сколько джоб одного шарда проверяется одновременно, если у шарда нет своего
+    concurrency: usize,
+    steal: Steal,
+    worker_id: String,
+    lease: Duration,
+    reap_interval: Duration,
+    poll_interval: Duration,
+    stats_interval: Duration,
+    heartbeat_interval: Duration,
+    /// общий backoff для шарда без своих переменных
+    backoff: Backoff,
+    /// переопределения шардов из реестра, заполняются в run
+    shard_settings: HashMap<Shard, ShardSettings>,
+    recheck: RecheckPolicy,
+    matrix: Matrix,
+    probes: Probes,
+    /// до какого номера искать картинки после old_pics_count, None - не искать
+    discover: Option<i16>,
+    spill: Spill,
+    /// после стольких отказов очереди пачка из spill откладывается в .failed
+    spill_attempts: u32,
+}
+
+impl Settings {
+    /// настройки шарда; без переопределений - общие
+    fn shard(&self, shard: &Shard) -> ShardSettings {
+        self.shard_settings
+            .get(shard)
+            .cloned()
+            .unwrap_or_else(|| ShardSettings {
+                concurrency: self.concurrency,
+                backoff: self.backoff.clone(),
+            })
+    }
+}
+
+/// настройки, которые задаются для отдельного шарда переменными
+/// с суффиксом из его имени: BACKOFF_BASE_SECS_SHARD_1 для shard_1;
+/// по ним обрабатываются и пачки, украденные из шарда другим процессом
+#[derive(Debug, Clone, PartialEq)]
+struct ShardSettings {
+    concurrency: usize,
+    backoff: Backoff,
+}
+
 const LOG_LEVEL: Level = Level::TRACE;
 
 const POSTGRES_DB: &str = "POSTGRES_DB";
@@ -42,9 +288,62 @@ const POSTGRES_PORT: &str = "POSTGRES_PORT";
 
 const DEBUG: &str = "DEBUG";
 
+/// один шард, если не задан SHARDS
 const SHARD: &str = "SHARD";
+/// shard_1,shard_2 или all - все шарды из реестра
+const SHARDS: &str = "SHARDS";
+/// джоб на шард за раз, по умолчанию WORKERS; SHARD_CONCURRENCY_SHARD_1 - только для shard_1
+const SHARD_CONCURRENCY: &str = "SHARD_CONCURRENCY";
+/// never или busiest, см. Steal
+const STEAL: &str = "STEAL";
+
+const WORKER_ID: &str = "WORKER_ID";
+const LEASE_SECS: &str = "LEASE_SECS";
+const REAP_INTERVAL_SECS: &str = "REAP_INTERVAL_SECS";
+/// как часто проверять шард, если уведомлений о новых джобах нет
+const POLL_INTERVAL_SECS: &str = "POLL_INTERVAL_SECS";
+const STATS_INTERVAL_SECS: &str = "STATS_INTERVAL_SECS";
+/// воркер, пропустивший 4 heartbeat'а подряд, считается упавшим и его джобы возвращаются в очередь
+const HEARTBEAT_INTERVAL_SECS: &str = "HEARTBEAT_INTERVAL_SECS";
+
+const BACKOFF_BASE_SECS: &str = "BACKOFF_BASE_SECS";
+const BACKOFF_MULTIPLIER: &str = "BACKOFF_MULTIPLIER";
+const BACKOFF_JITTER: &str = "BACKOFF_JITTER";
+const BACKOFF_CAP_SECS: &str = "BACKOFF_CAP_SECS";
+
+/// через сколько дней перепроверять готовые номенклатуры
+const RECHECK_AFTER_DAYS: &str = "RECHECK_AFTER_DAYS";
+/// то же для номенклатур, потерявших картинки
+const RECHECK_REGRESSED_AFTER_DAYS: &str = "RECHECK_REGRESSED_AFTER_DAYS";
+
+/// размеры картинок через запятую, первый - основной: big,c516x688,c246x328,tm
+const IMAGE_SIZES: &str = "IMAGE_SIZES";
+/// форматы через запятую, первый - основной: jpg,webp,avif
+const IMAGE_FORMATS: &str = "IMAGE_FORMATS";
+
+/// head, range или get, см. Probe
+const PROBE: &str = "PROBE";
+/// способ для отдельных хостов: images.wbstatic.net=range,cdn.example.com=get
+const PROBE_HOSTS: &str = "PROBE_HOSTS";
+
+/// до какого номера искать новые картинки после old_pics_count, 0 - не искать
+const DISCOVER_MAX_PICS: &str = "DISCOVER_MAX_PICS";
 
-static mut DEBUG_FLAG: bool = true;
+/// postgres или memory
+const QUEUE: &str = "QUEUE";
+/// csv nm_id,old_pics_count для очереди в памяти
+const SEED_FILE: &str = "SEED_FILE";
+/// куда откладывать результаты, которые не удалось записать в очередь
+const SPILL_FILE: &str = "SPILL_FILE";
+/// сколько раз очередь может отвергнуть отложенную пачку, прежде чем она уйдет в .failed
+const SPILL_MAX_ATTEMPTS: &str = "SPILL_MAX_ATTEMPTS";
+
+static DEBUG_FLAG: AtomicBool = AtomicBool::new(true);
+// счетчики с момента запуска, уходят в workers вместе с heartbeat
+static FINISHED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+/// результаты, отброшенные из-за потерянного захвата
+static LOST_CLAIMS: AtomicU64 = AtomicU64::new(0);
 
 fn main() -> anyhow::Result<()> {
     let rt = Builder::new_multi_thread().enable_all().build()?;
@@ -58,13 +357,86 @@ fn main() -> anyhow::Result<()> {
     let q_port = var(POSTGRES_PORT).unwrap_or_else(|_| String::from("5433"));
     let q_database = var(POSTGRES_DB).unwrap_or_else(|_| String::from("content"));
 
-    let shard = var(SHARD).unwrap_or_else(|_| String::from("shard_1"));
+    let queue_kind = var(QUEUE).unwrap_or_else(|_| String::from("postgres"));
+    let shards = match var(SHARDS) {
+        Ok(shards) if shards.trim() == "all" => Vec::new(),
+        Ok(shards) => shards
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Shard::new)
+            .collect::<anyhow::Result<Vec<Shard>>>()?,
+        Err(_) => vec![Shard::new(
+            &var(SHARD).unwrap_or_else(|_| String::from("shard_1")),
+        )?],
+    };
+    let concurrency = var(SHARD_CONCURRENCY)
+        .unwrap_or_else(|_| WORKERS.to_string())
+        .parse::<usize>()?;
+    let steal = var(STEAL)
+        .unwrap_or_else(|_| String::from("never"))
+        .parse::<Steal>()?;
+
+    let worker_id = var(WORKER_ID)
+        .or_else(|_| var("HOSTNAME"))
+        .unwrap_or_else(|_| format!("process-{}", std::process::id()));
+    let lease = Duration::from_secs(
+        var(LEASE_SECS)
+            .unwrap_or_else(|_| String::from("300"))
+            .parse::<u64>()?,
+    );
+    let reap_interval = Duration::from_secs(
+        var(REAP_INTERVAL_SECS)
+            .unwrap_or_else(|_| String::from("60"))
+            .parse::<u64>()?,
+    );
+
+    let poll_interval = Duration::from_secs(
+        var(POLL_INTERVAL_SECS)
+            .unwrap_or_else(|_| String::from("10"))
+            .parse::<u64>()?,
+    );
 
-    unsafe {
-        DEBUG_FLAG = var(DEBUG)
+    let stats_interval = Duration::from_secs(
+        var(STATS_INTERVAL_SECS)
+            .unwrap_or_else(|_| String::from("60"))
+            .parse::<u64>()?,
+    );
+
+    let heartbeat_interval = Duration::from_secs(
+        var(HEARTBEAT_INTERVAL_SECS)
+            .unwrap_or_else(|_| String::from("15"))
+            .parse::<u64>()?,
+    );
+
+    let backoff = backoff_from_env(None)?;
+    let recheck = recheck_from_env()?;
+    let matrix = Matrix::new(
+        &var(IMAGE_SIZES).unwrap_or_else(|_| String::from("big")),
+        &var(IMAGE_FORMATS).unwrap_or_else(|_| String::from("jpg")),
+    )?;
+    let probes = probes_from_env()?;
+    let discover = Some(
+        var(DISCOVER_MAX_PICS)
+            .unwrap_or_else(|_| String::from("0"))
+            .parse::<i16>()?,
+    )
+    .filter(|max| *max > 0);
+
+    let spill = Spill::new(var(SPILL_FILE).unwrap_or_else(|_| match shards.as_slice() {
+        [shard] => format!("{shard}.spill.ndjson"),
+        _ => format!("{worker_id}.spill.ndjson"),
+    }));
+    let spill_attempts = var(SPILL_MAX_ATTEMPTS)
+        .unwrap_or_else(|_| String::from("10"))
+        .parse::<u32>()?;
+
+    DEBUG_FLAG.store(
+        var(DEBUG)
             .unwrap_or_else(|_| String::from("true"))
-            .parse::<bool>()?;
-    }
+            .parse::<bool>()?,
+        Ordering::Relaxed,
+    );
 
     info!("user=[{q_user}]");
     info!("password=[{q_password}]");
@@ -72,14 +444,51 @@ fn main() -> anyhow::Result<()> {
     info!("port=[{q_port}]");
     info!("database=[{q_database}]");
     info!("==============");
-    info!("shard=[{shard}]");
-    unsafe {
-        info!("DEBUG_FLAG=[{DEBUG_FLAG}]");
-    }
+    info!("queue=[{queue_kind}]");
+    info!(
+        "shards=[{}]",
+        shards
+            .iter()
+            .map(Shard::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    info!("concurrency=[{concurrency}]");
+    info!("steal=[{steal:?}]");
+    info!("worker_id=[{worker_id}]");
+    info!("lease=[{lease:?}]");
+    info!("reap_interval=[{reap_interval:?}]");
+    info!("poll_interval=[{poll_interval:?}]");
+    info!("stats_interval=[{stats_interval:?}]");
+    info!("heartbeat_interval=[{heartbeat_interval:?}]");
+    info!("backoff=[{backoff:?}]");
+    info!("recheck=[{recheck:?}]");
+    info!("matrix=[{matrix:?}]");
+    info!("probes=[{probes:?}]");
+    info!("discover=[{discover:?}]");
+    info!("spill=[{}]", spill.path().display());
+    info!("spill_attempts=[{spill_attempts}]");
+    info!("DEBUG_FLAG=[{}]", DEBUG_FLAG.load(Ordering::Relaxed));
 
-    let queue = rt.block_on(async {
-        sqlx_queue::SqlxPool::new(q_host, q_port, q_user, q_password, q_database).await
-    })?;
+    let settings = Settings {
+        shards,
+        concurrency,
+        steal,
+        worker_id,
+        lease,
+        reap_interval,
+        poll_interval,
+        stats_interval,
+        heartbeat_interval,
+        backoff,
+        shard_settings: HashMap::new(),
+        recheck,
+        matrix,
+        probes,
+        discover,
+        spill,
+        spill_attempts,
+    };
 
     let cli = {
         let svc = reqwest::Client::builder().build().unwrap();
@@ -88,94 +497,557 @@ fn main() -> anyhow::Result<()> {
             .service(service_fn(move |req| svc.execute(req)))
     };
 
-    rt.spawn({
-        async move {
-            process(shard, queue, cli).await;
+    match queue_kind.as_str() {
+        "memory" => {
+            if settings.shards.is_empty() {
+                anyhow::bail!("memory queue has no shard registry, list SHARDS explicitly");
+            }
+            let queue = InMemoryQueue::new();
+            for shard in &settings.shards {
+                rt.block_on(queue.create_shard(shard))?;
+            }
+            if let Ok(seed_file) = var(SEED_FILE) {
+                // раскладка по шардам та же, что у import
+                let mut by_shard = vec![Vec::new(); settings.shards.len()];
+                for row in import::read_rows(File::open(&seed_file)?) {
+                    let row = row?;
+                    by_shard[import::shard_index(row.0, settings.shards.len())].push(row);
+                }
+                let mut seeded = 0;
+                for (shard, rows) in settings.shards.iter().zip(by_shard) {
+                    seeded += queue.seed(shard, rows)?;
+                }
+                info!("{seeded} nomenclatures were seeded from {seed_file}");
+            }
+            run(&rt, settings, queue, cli)
         }
-    });
+        "postgres" => {
+            let queue = rt.block_on(async {
+                sqlx_queue::SqlxPool::new(q_host, q_port, q_user, q_password, q_database).await
+            })?;
+            rt.block_on(queue.migrate())?;
+            info!("migrations applied");
+            run(&rt, settings, queue, cli)
+        }
+        other => anyhow::bail!("unknown queue [{other}], expected postgres|memory"),
+    }
+}
+
+/// запускает фоновые задачи и работает до ctrl-c или SIGTERM, после чего останавливает их,
+/// возвращает в очередь захваченные джобы и снимает воркера с регистрации
+fn run<Q, C>(rt: &Runtime, settings: Settings, queue: Q, client: C) -> anyhow::Result<()>
+where
+    Q: Queue + Clone + 'static,
+    C: Service<Request, Response = Response, Error = BoxError> + Clone + Send + Sync + 'static,
+    C::Future: Send,
+{
+    // неизвестный шард - ошибка конфигурации, до захвата джоб
+    let shards = if settings.shards.is_empty() {
+        rt.block_on(queue.shards())?
+    } else {
+        rt.block_on(try_join_all(
+            settings
+                .shards
+                .iter()
+                .map(|shard| queue.shard(shard.as_str())),
+        ))?
+    };
+    if shards.is_empty() {
+        anyhow::bail!("no shards to process");
+    }
+    info!(
+        "processing shards [{}]",
+        shards
+            .iter()
+            .map(Shard::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    // переопределения нужны и для шардов, из которых джобы только крадутся
+    let shard_settings = rt
+        .block_on(queue.shards())?
+        .iter()
+        .chain(&shards)
+        .map(|shard| Ok((shard.clone(), shard_settings_from_env(shard)?)))
+        .collect::<anyhow::Result<HashMap<Shard, ShardSettings>>>()?;
+    for (shard, overrides) in &shard_settings {
+        if overrides.concurrency != settings.concurrency || overrides.backoff != settings.backoff {
+            info!("{shard} settings=[{overrides:?}]");
+        }
+    }
+    let settings = Settings {
+        shards,
+        shard_settings,
+        ..settings
+    };
+    let worker_id = settings.worker_id.clone();
+    // docker stop шлет SIGTERM
+    let mut terminate = rt.block_on(async { signal(SignalKind::terminate()) })?;
+
+    let mut tasks = vec![
+        rt.spawn(heartbeat(settings.clone(), queue.clone())),
+        rt.spawn(replay(settings.clone(), queue.clone())),
+    ];
+
+    // у каждого шарда свой цикл с одинаковым лимитом, поэтому большой шард
+    // не вытесняет маленькие; пул соединений и http клиент общие
+    for shard in &settings.shards {
+        tasks.push(rt.spawn(reap(
+            shard.clone(),
+            queue.clone(),
+            settings.reap_interval,
+            settings.heartbeat_interval * 4,
+        )));
+
+        tasks.push(rt.spawn(stats(shard.clone(), queue.clone(), settings.stats_interval)));
+
+        tasks.push(rt.spawn(process(
+            settings.clone(),
+            shard.clone(),
+            queue.clone(),
+            client.clone(),
+        )));
+    }
 
     // для теста секционирования
     // rt.block_on(async { sleep(Duration::from_secs(180)).await });
 
     rt.block_on(async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("failed to listen for event");
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res.expect("failed to listen for event"),
+            _ = terminate.recv() => (),
+        }
     });
 
-    info!("received ctrl-c event");
+    info!("received shutdown signal");
+
+    // пачки в работе бросаются: их джобы освобождает release_claims, а heartbeat
+    // после остановки зарегистрировал бы воркера заново
+    for task in &tasks {
+        task.abort();
+    }
+    rt.block_on(join_all(tasks));
+
+    // джобы могли быть украдены из любого шарда реестра
+    let shards = rt.block_on(queue.shards()).unwrap_or_else(|err| {
+        error!("run>queue.shards: {err}");
+        settings.shards.clone()
+    });
+    for shard in shards {
+        match rt.block_on(queue.release_claims(&shard, &worker_id)) {
+            Ok(0) => (),
+            Ok(released) => info!("{shard} released {released} claimed jobs"),
+            Err(err) => error!("run>queue.release_claims of {shard}: {err}"),
+        }
+    }
+    rt.block_on(queue.deregister_worker(&worker_id))?;
+    info!("worker {worker_id} was deregistered");
 
     Ok(())
 }
 
-async fn process<C>(shard: String, queue: impl Queue, client: C)
+/// политика повторов; для шарда сначала читаются переменные с его суффиксом,
+/// потом общие, незаданные берутся из Backoff::default
+fn backoff_from_env(shard: Option<&Shard>) -> anyhow::Result<Backoff> {
+    let default = Backoff::default();
+    let var = |name: &str| shard_var(name, shard);
+    Ok(Backoff {
+        base: match var(BACKOFF_BASE_SECS) {
+            Ok(v) => Duration::from_secs_f64(v.parse::<f64>()?),
+            Err(_) => default.base,
+        },
+        multiplier: match var(BACKOFF_MULTIPLIER) {
+            Ok(v) => v.parse::<f64>()?,
+            Err(_) => default.multiplier,
+        },
+        jitter: match var(BACKOFF_JITTER) {
+            Ok(v) => v.parse::<f64>()?,
+            Err(_) => default.jitter,
+        },
+        cap: match var(BACKOFF_CAP_SECS) {
+            Ok(v) => Duration::from_secs_f64(v.parse::<f64>()?),
+            Err(_) => default.cap,
+        },
+    })
+}
+
+/// переменная шарда NAME_SHARD_1, если ее нет - общая NAME
+fn shard_var(name: &str, shard: Option<&Shard>) -> Result<String, std::env::VarError> {
+    match shard {
+        Some(shard) => {
+            var(format!("{name}_{}", shard.as_str().to_ascii_uppercase())).or_else(|_| var(name))
+        }
+        None => var(name),
+    }
+}
+
+fn shard_settings_from_env(shard: &Shard) -> anyhow::Result<ShardSettings> {
+    Ok(ShardSettings {
+        concurrency: shard_var(SHARD_CONCURRENCY, Some(shard))
+            .unwrap_or_else(|_| WORKERS.to_string())
+            .parse::<usize>()?,
+        backoff: backoff_from_env(Some(shard))?,
+    })
+}
+
+/// политика перепроверки, незаданные переменные берутся из RecheckPolicy::default
+fn recheck_from_env() -> anyhow::Result<RecheckPolicy> {
+    let default = RecheckPolicy::default();
+    let days = |v: String| -> anyhow::Result<Duration> {
+        Ok(Duration::from_secs_f64(v.parse::<f64>()? * 24.0 * 3600.0))
+    };
+    Ok(RecheckPolicy {
+        after: match var(RECHECK_AFTER_DAYS) {
+            Ok(v) => days(v)?,
+            Err(_) => default.after,
+        },
+        regressed_after: match var(RECHECK_REGRESSED_AFTER_DAYS) {
+            Ok(v) => days(v)?,
+            Err(_) => default.regressed_after,
+        },
+    })
+}
+
+/// способ проверки по умолчанию и переопределения для хостов
+fn probes_from_env() -> anyhow::Result<Probes> {
+    let default = var(PROBE)
+        .unwrap_or_else(|_| String::from("head"))
+        .parse::<Probe>()?;
+    let hosts = var(PROBE_HOSTS)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.split_once('=') {
+            Some((host, probe)) => Ok((host.trim().to_string(), probe.trim().parse::<Probe>()?)),
+            None => anyhow::bail!("invalid {PROBE_HOSTS} entry [{s}], expected host=probe"),
+        })
+        .collect::<anyhow::Result<HashMap<String, Probe>>>()?;
+    Ok(Probes::new(default, hosts))
+}
+
+/// периодически возвращает в очередь джобы упавших или остановленных реплик
+async fn reap(shard: Shard, queue: impl Queue, period: Duration, stale_after: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match queue.reap_dead_workers(&shard, stale_after).await {
+            Ok(0) => trace!("no jobs of dead workers in {shard}"),
+            Ok(n) => info!("{n} jobs of dead workers were returned to {shard}"),
+            Err(err) => error!("reap>queue.reap_dead_workers: {err}"),
+        }
+        match queue.reap_expired(&shard).await {
+            Ok(0) => trace!("no expired leases in {shard}"),
+            Ok(n) => info!("{n} expired leases were returned to {shard}"),
+            Err(err) => error!("reap>queue.reap_expired: {err}"),
+        }
+    }
+}
+
+/// регистрирует воркера в workers и периодически обновляет heartbeat со счетчиками
+async fn heartbeat(settings: Settings, queue: impl Queue) {
+    let mut worker = Worker {
+        worker_id: settings.worker_id,
+        hostname: var("HOSTNAME").unwrap_or_else(|_| String::from("unknown")),
+        shards: settings.shards.iter().map(Shard::to_string).collect(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        started_at: Utc::now(),
+        heartbeat_at: Utc::now(),
+        finished: 0,
+        failed: 0,
+        lost_claims: 0,
+    };
+    let mut interval = tokio::time::interval(settings.heartbeat_interval);
+    loop {
+        interval.tick().await;
+        worker.finished = FINISHED.load(Ordering::Relaxed) as i64;
+        worker.failed = FAILED.load(Ordering::Relaxed) as i64;
+        worker.lost_claims = LOST_CLAIMS.load(Ordering::Relaxed) as i64;
+        if let Err(err) = queue.heartbeat(&worker).await {
+            error!("heartbeat>queue.heartbeat: {err}");
+        }
+    }
+}
+
+/// периодически пишет в лог прогресс шарда
+async fn stats(shard: Shard, queue: impl Queue, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match queue.stats(&shard).await {
+            Ok(stats) => info!(
+                "{shard}: {stats} lost_claims={}",
+                LOST_CLAIMS.load(Ordering::Relaxed)
+            ),
+            Err(err) => error!("stats>queue.stats: {err}"),
+        }
+    }
+}
+
+async fn process<C>(settings: Settings, shard: Shard, queue: impl Queue, client: C)
 where
     C: Service<Request, Response = Response, Error = BoxError> + Clone + Send + 'static,
     C::Future: Send,
 {
+    let mut victim = None;
+    loop {
+        if process_batch(&settings, &shard, &queue, client.clone()).await == 0
+            && steal(&settings, &shard, &queue, &mut victim, client.clone()).await == 0
+        {
+            trace!("[nms] of {shard} is empty");
+            match queue.wait_for_jobs(&shard, settings.poll_interval).await {
+                Ok(true) => trace!("woken up by new jobs in {shard}"),
+                Ok(false) => (),
+                Err(err) => {
+                    error!("work>queue.wait_for_jobs: {err}");
+                    sleep(Duration::from_millis(500)).await;
+                }
+            }
+            continue;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// пока свой шард пуст, забирает пачку из шарда, который процесс не обрабатывает;
+/// донор выбирается заново, только когда джобы в нем закончились
+async fn steal<C>(
+    settings: &Settings,
+    shard: &Shard,
+    queue: &impl Queue,
+    victim: &mut Option<Shard>,
+    client: C,
+) -> usize
+where
+    C: Service<Request, Response = Response, Error = BoxError> + Clone + Send + 'static,
+    C::Future: Send,
+{
+    if settings.steal == Steal::Never {
+        return 0;
+    }
+    if let Some(victim) = victim {
+        let pulled = process_batch(settings, victim, queue, client.clone()).await;
+        if pulled > 0 {
+            return pulled;
+        }
+    }
+    *victim = match queue.busiest_shard(&settings.shards).await {
+        Ok(victim) => victim,
+        Err(err) => {
+            error!("work>queue.busiest_shard: {err}");
+            None
+        }
+    };
+    match victim {
+        Some(victim) => {
+            info!("{shard} is drained, stealing from {victim}");
+            process_batch(settings, victim, queue, client).await
+        }
+        None => 0,
+    }
+}
+
+/// периодически дописывает в очередь пачки, отложенные в spill; одна задача на процесс
+async fn replay(settings: Settings, queue: impl Queue) {
+    let mut interval = tokio::time::interval(settings.poll_interval);
     loop {
-        let nms = match queue.pull(&shard, WORKERS as i64).await {
-            Ok(nms) => nms,
+        interval.tick().await;
+        if let Err(err) = replay_spill(&settings, &queue).await {
+            error!("replay>replay_spill: {err}");
+        }
+    }
+}
+
+/// дописывает в очередь пачки, отложенные в spill, пока она была недоступна;
+/// если postgres все еще недоступен, останавливается и оставшиеся пачки ждут в файле,
+/// отвергнутая очередью пачка уходит в конец файла, см. Spill::defer_front
+async fn replay_spill(settings: &Settings, queue: &impl Queue) -> anyhow::Result<()> {
+    for (shard, batch) in settings.spill.load()? {
+        let lost = match queue
+            .commit_batch(&shard, &batch, &settings.shard(&shard).backoff)
+            .await
+        {
+            Ok(lost) => lost,
+            Err(err) if unavailable(&err) => return Err(err),
             Err(err) => {
-                error!("work>queue.pull: {err}");
-                sleep(Duration::from_millis(500)).await;
-                Vec::new()
+                let attempts = settings.spill.defer_front(settings.spill_attempts)?;
+                if attempts >= settings.spill_attempts {
+                    error!(
+                        "replay>commit_batch: {err}, spilled results of {shard} were moved to {}",
+                        settings.spill.failed_path().display()
+                    );
+                } else {
+                    warn!("replay>commit_batch: {err}, spilled results of {shard} were deferred, attempt {attempts}");
+                }
+                continue;
             }
         };
+        settings.spill.pop_front()?;
+        report_lost(&shard, &lost);
+        info!(
+            "{} spilled results of {shard} were committed",
+            batch.finished.len() + batch.failed.len()
+        );
+    }
+    Ok(())
+}
 
-        // println!("{:?}", nms);
+/// postgres недоступен, а не отверг запрос: пачку стоит повторить без счета попыток
+fn unavailable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<sqlx::Error>(),
+            Some(
+                sqlx::Error::Io(_)
+                    | sqlx::Error::Tls(_)
+                    | sqlx::Error::PoolTimedOut
+                    | sqlx::Error::PoolClosed
+                    | sqlx::Error::WorkerCrashed
+            )
+        )
+    })
+}
 
-        if nms.is_empty() {
-            trace!("[nms] is empty");
-            sleep(Duration::from_millis(500)).await;
-            continue;
+/// джобы, которые за время проверки забрал другой воркер или сбросили вручную;
+/// их результаты не записаны
+fn report_lost(shard: &Shard, lost: &[Claim]) {
+    if lost.is_empty() {
+        return;
+    }
+    LOST_CLAIMS.fetch_add(lost.len() as u64, Ordering::Relaxed);
+    warn!(
+        "{} claims of {shard} were lost, results discarded: {:?}",
+        lost.len(),
+        lost.iter().map(|claim| claim.nm_id).collect::<Vec<_>>()
+    );
+}
+
+/// забирает одну пачку джоб шарда, проверяет ее и записывает результаты; возвращает размер пачки
+async fn process_batch<C>(
+    settings: &Settings,
+    shard: &Shard,
+    queue: &impl Queue,
+    client: C,
+) -> usize
+where
+    C: Service<Request, Response = Response, Error = BoxError> + Clone + Send + 'static,
+    C::Future: Send,
+{
+    // пачка чужого шарда обрабатывается по его настройкам
+    let ShardSettings {
+        concurrency,
+        backoff,
+    } = settings.shard(shard);
+    let Settings {
+        worker_id,
+        lease,
+        recheck,
+        matrix,
+        probes,
+        discover,
+        spill,
+        ..
+    } = settings;
+
+    let nms = match queue
+        .pull(shard, worker_id, concurrency as i64, *lease, recheck)
+        .await
+    {
+        Ok(nms) => nms,
+        Err(err) => {
+            error!("work>queue.pull: {err}");
+            return 0;
         }
+    };
 
-        trace!("{} had been fetched", nms.len());
+    // println!("{:?}", nms);
 
-        let (finished_tx, finished_rx) = flume::bounded(3000);
-        let (failed_tx, failed_rx) = flume::bounded(3000);
+    if nms.is_empty() {
+        return 0;
+    }
+    let pulled = nms.len();
 
-        stream::iter(nms)
-            .for_each_concurrent(WORKERS, |nm| async {
-                // println!("here");
-                let client = client.clone();
-                let (nm, result) = worker_fn(nm, client).await;
-                match result {
-                    Ok(res) => {
-                        if let Err(SendError(id)) = finished_tx.send_async(res).await {
-                            error!("sending error {:?}", id);
-                        };
-                    }
-                    Err(err) => {
-                        error!("run_worker>handle_nm[{nm}]: {err}");
-                        if let Err(SendError(nm)) = failed_tx.send_async(nm).await {
-                            error!("sending error {}", nm);
-                        };
-                    }
+    trace!("{} had been fetched", pulled);
+
+    let (finished_tx, finished_rx) = flume::bounded(3000);
+    let (failed_tx, failed_rx) = flume::bounded(3000);
+    let (attempts_tx, attempts_rx) = flume::bounded(3000);
+
+    let claims: Vec<Claim> = nms.iter().map(Nomenclature::claim).collect();
+    let check = stream::iter(nms).for_each_concurrent(concurrency, |nm| async {
+        // println!("here");
+        let client = client.clone();
+        let claim = nm.claim();
+        let (attempt, result) = worker_fn(shard, matrix, probes, *discover, nm, client).await;
+        if let Err(SendError(attempt)) = attempts_tx.send_async(attempt).await {
+            error!("sending error {}", attempt.nm_id);
+        };
+        match result {
+            Ok((_, new_pics_count, good_links, pics, variants)) => {
+                let res = (claim, new_pics_count, good_links, pics, variants);
+                if let Err(SendError(res)) = finished_tx.send_async(res).await {
+                    error!("sending error {:?}", res);
+                };
+            }
+            Err(failure) => {
+                error!("run_worker>handle_nm[{}]: {failure}", claim.nm_id);
+                if let Err(SendError((claim, _))) = failed_tx.send_async((claim, failure)).await {
+                    error!("sending error {}", claim.nm_id);
                 };
-            })
-            .await;
-        drop(finished_tx);
-        drop(failed_tx);
-
-        let finished_nms = finished_rx.into_iter().collect::<Vec<(i64, i16, String)>>();
-        if !finished_nms.is_empty() {
-            info!("{} nomenclatures were finished", finished_nms.len());
-            if let Err(err) = queue.batch_finish_jobs(&shard, finished_nms).await {
-                error!("batch_finish_jobs {}", err);
             }
         };
+    });
+    // пачка может проверяться дольше аренды, а reap_expired отдал бы ее другому воркеру
+    tokio::select! {
+        () = check => (),
+        () = keep_leases(queue, shard, &claims, *lease) => (),
+    }
+    drop(finished_tx);
+    drop(failed_tx);
+    drop(attempts_tx);
 
-        let failed_nms = failed_rx.into_iter().collect::<Vec<i64>>();
-        if !failed_nms.is_empty() {
-            info!("{} nomenclatures were failed", failed_nms.len());
-            if let Err(err) = queue.batch_fail_jobs(&shard, failed_nms).await {
-                error!("batch_fail_jobs {}", err);
+    let batch = Batch {
+        finished: finished_rx.into_iter().collect(),
+        failed: failed_rx.into_iter().collect(),
+        attempts: attempts_rx.into_iter().collect(),
+    };
+    info!(
+        "{} nomenclatures of {shard} were finished, {} were failed",
+        batch.finished.len(),
+        batch.failed.len()
+    );
+    match queue.commit_batch(shard, &batch, &backoff).await {
+        Ok(lost) => {
+            FINISHED.fetch_add(batch.finished.len() as u64, Ordering::Relaxed);
+            FAILED.fetch_add(batch.failed.len() as u64, Ordering::Relaxed);
+            report_lost(shard, &lost);
+        }
+        Err(err) => {
+            error!("work>queue.commit_batch: {err}");
+            // строки останутся в работе до истечения аренды, результаты допишет replay_spill
+            match spill.append(shard, &batch) {
+                Ok(()) => warn!(
+                    "results of {shard} were spilled to {}",
+                    spill.path().display()
+                ),
+                Err(err) => error!("work>spill.append: {err}, results are lost: {batch:?}"),
             }
-        };
+        }
+    }
 
-        sleep(Duration::from_millis(100)).await;
+    pulled
+}
+
+/// продлевает аренду пачки каждую треть lease, пока ее проверка не закончится
+async fn keep_leases(queue: &impl Queue, shard: &Shard, claims: &[Claim], lease: Duration) {
+    let mut interval = tokio::time::interval(lease / 3);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(err) = queue.extend_lease(shard, claims, lease).await {
+            error!("work>queue.extend_lease: {err}");
+        }
     }
 }
 
@@ -196,112 +1068,321 @@ fn init_tracing() {
     }
 }
 
-async fn worker_fn<C>(nm: Nomenclature, cli: C) -> (i64, anyhow::Result<(i64, i16, String)>)
+/// проверяет картинки номенклатуры во всех размерах и форматах; попытка возвращается
+/// при любом исходе, в результате кроме непрерывного ряда - номера всех найденных
+/// в основном варианте картинок и найденные варианты каждой картинки.
+/// с discover ищет картинки и после old_pics_count, не дальше заданного номера
+async fn worker_fn<C>(
+    shard: &Shard,
+    matrix: &Matrix,
+    probes: &Probes,
+    discover: Option<i16>,
+    nm: Nomenclature,
+    cli: C,
+) -> (
+    Attempt,
+    Result<(i64, i16, String, Vec<i16>, Variants), Failure>,
+)
 where
     C: Service<Request, Response = Response, Error = BoxError> + Clone + Send + 'static,
     C::Future: Send,
 {
-    let links = generate_urls_by_nm_id(&nm.nm_id, &nm.old_pics_count);
+    let mut links = matrix.links(nm.nm_id, 1..=nm.old_pics_count);
     trace!("generated links: {:?}", links);
+    let order = matrix.variants();
 
-    let n = links.len();
     let nm_id = nm.nm_id;
+    let mut attempt = Attempt {
+        shard: shard.to_string(),
+        nm_id,
+        worker_id: nm.worker_id.clone().unwrap_or_default(),
+        started_at: Utc::now(),
+        finished_at: Utc::now(),
+        statuses: Vec::new(),
+        outcome: String::from("finished"),
+        error: None,
+    };
 
-    let mut futures: FuturesUnordered<_> = (0..n)
-        .map(|idx| {
-            let client = cli.clone();
-            let future = do_request(idx, client, links[idx].clone());
-            tokio::spawn(future)
-        })
-        .collect();
+    let checked = async {
+        let mut found = check_links(&links, &order[0], probes, &cli, &mut attempt.statuses).await?;
+        let max = match discover {
+            Some(max) if max > nm.old_pics_count => max,
+            _ => return Ok::<_, Failure>(found),
+        };
+        let last = discover_last_pic(
+            nm.old_pics_count,
+            max,
+            |idx| do_request(0, cli.clone(), probes.clone(), matrix.url(nm_id, idx)),
+            &mut attempt.statuses,
+        )
+        .await?;
+        if last > nm.old_pics_count {
+            trace!("nm {nm_id} discovered pics up to {last}");
+            let discovered = matrix.links(nm_id, nm.old_pics_count + 1..=last);
+            found.extend(
+                check_links(&discovered, &order[0], probes, &cli, &mut attempt.statuses).await?,
+            );
+            links.extend(discovered);
+        }
+        Ok(found)
+    };
+    let found = match checked.await {
+        Ok(found) => found,
+        Err(failure) => {
+            error!("rawr error: {failure}");
+            attempt.finished_at = Utc::now();
+            attempt.outcome = String::from("failed");
+            attempt.error = Some(failure.to_string());
+            return (attempt, Err(failure));
+        }
+    };
 
     let mut res: Vec<String> = Vec::new();
     let mut indexes = Vec::new();
+    let mut variants = Variants::new();
+
+    for ((image_idx, variant, url), _) in links.iter().zip(found).filter(|(_, found)| *found) {
+        if *variant == order[0] {
+            res.push(url.clone());
+            indexes.push(*image_idx);
+        }
+        variants
+            .entry(*image_idx)
+            .or_default()
+            .push(variant.clone());
+    }
+
+    // println!("worker_fn 1");
+
+    trace!("nm {} indexes {:?}", nm.nm_id, indexes);
+
+    indexes.sort_unstable();
+    let new_pics_count = get_pics_count(indexes.clone());
+    trace!("nm {} new_pics_count {:?}", nm.nm_id, new_pics_count);
+    let good_links = res.join(";");
+    trace!("nm {} good_links {:?}", nm.nm_id, good_links);
+
+    trace!("nm {} variants {:?}", nm.nm_id, variants);
+
+    attempt.finished_at = Utc::now();
+    (
+        attempt,
+        Ok((nm.nm_id, new_pics_count, good_links, indexes, variants)),
+    )
+}
+
+/// проверяет ссылки параллельно, статусы дописываются в statuses в порядке ссылок;
+/// возвращает, какие из ссылок нашлись. ошибкой проверка заканчивается только
+/// на основном варианте primary, остальные при ошибке считаются ненайденными
+async fn check_links<C>(
+    links: &[(i16, String, String)],
+    primary: &str,
+    probes: &Probes,
+    cli: &C,
+    statuses: &mut Vec<i16>,
+) -> Result<Vec<bool>, Failure>
+where
+    C: Service<Request, Response = Response, Error = BoxError> + Clone + Send + 'static,
+    C::Future: Send,
+{
+    let offset = statuses.len();
+    statuses.resize(offset + links.len(), 0);
+    let mut found = vec![false; links.len()];
+
+    let mut futures: FuturesUnordered<_> = links
+        .iter()
+        .enumerate()
+        .map(|(idx, (_, _, url))| {
+            let future = do_request(idx, cli.clone(), probes.clone(), url.clone());
+            tokio::spawn(future)
+        })
+        .collect();
 
     while let Some(join_result) = futures.next().await {
-        let (image_idx, result) = match join_result {
+        let (link_idx, status, result) = match join_result {
             Ok(r) => r,
             Err(e) => {
                 error!("cant join to handle {e}");
                 continue;
             }
         };
-        match result {
-            Ok(true) => {
-                let bucket = nm_id / 10000 * 10000;
-                res.push(format_url(
-                    SIZES[0].0,
-                    bucket,
-                    &nm_id,
-                    (image_idx + 1) as i16,
-                    JPG,
-                ));
-                indexes.push((image_idx + 1) as i16);
-            }
-            Ok(false) => (),
-            Err(e) => {
-                error!("rawr error: {e}");
-                return (nm.nm_id, Err(anyhow!(e.to_string())));
+        statuses[offset + link_idx] = status_code(status);
+        let (_, variant, _) = &links[link_idx];
+        found[link_idx] = match result {
+            // без дополнительного варианта картинка есть, он просто не засчитывается
+            Err(failure) if variant != primary => {
+                warn!("variant {variant} is skipped: {failure}");
+                false
             }
+            result => result?,
         };
     }
+    Ok(found)
+}
 
-    // println!("worker_fn 1");
-
-    trace!("nm {} indexes {:?}", nm.nm_id, indexes);
-
-    let new_pics_count = get_pics_count(indexes);
-    trace!("nm {} new_pics_count {:?}", nm.nm_id, new_pics_count);
-    let good_links = res.join(";");
-    trace!("nm {} good_links {:?}", nm.nm_id, good_links);
+/// ищет номер последней картинки после known: шагами 1, 2, 4... от последней найденной
+/// до первого промаха или max, затем бинарным поиском между ними; картинки считаются
+/// идущими подряд, если ничего не нашлось - возвращает known
+async fn discover_last_pic<F, Fut>(
+    known: i16,
+    max: i16,
+    mut exists: F,
+    statuses: &mut Vec<i16>,
+) -> Result<i16, Failure>
+where
+    F: FnMut(i16) -> Fut,
+    Fut: Future<Output = (usize, Option<hyper::StatusCode>, Result<bool, Failure>)>,
+{
+    let mut probe = |idx: i32| {
+        let future = exists(idx as i16);
+        async move {
+            let (_, status, result) = future.await;
+            (status, result)
+        }
+    };
+    let (mut lo, max) = (known as i32, max as i32);
+    let mut step = 1;
+    let mut hi = loop {
+        if lo >= max {
+            return Ok(lo as i16);
+        }
+        let idx = (lo + step).min(max);
+        let (status, result) = probe(idx).await;
+        statuses.push(status_code(status));
+        if !result? {
+            break idx;
+        }
+        lo = idx;
+        step *= 2;
+    };
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        let (status, result) = probe(mid).await;
+        statuses.push(status_code(status));
+        if result? {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(lo as i16)
+}
 
-    (nm.nm_id, Ok((nm.nm_id, new_pics_count, good_links)))
+/// код ответа для попытки, 0 - ответа не было
+fn status_code(status: Option<hyper::StatusCode>) -> i16 {
+    status.map_or(0, |status| status.as_u16() as i16)
 }
 
-async fn do_request<C>(pic_idx: usize, mut client: C, url: String) -> (usize, anyhow::Result<bool>)
+/// Ok(true) - картинка есть, Ok(false) - 404; при отказе хоста от HEAD (405, 501)
+/// и при 4xx на HEAD от непроверенного хоста запрос повторяется через Range,
+/// вывод о хосте запоминается в probes. вместе с результатом отдает код последнего
+/// ответа, None - ответа не было
+async fn do_request<C>(
+    pic_idx: usize,
+    mut client: C,
+    probes: Probes,
+    url: String,
+) -> (usize, Option<hyper::StatusCode>, Result<bool, Failure>)
 where
     C: Service<Request, Response = Response, Error = BoxError>,
 {
     // trace!("do_request started {url}");
-    let request = match reqwest::Client::new()
-        .get(&url)
-        .timeout(std::time::Duration::from_secs(15))
-        .build()
-    {
-        Ok(r) => r,
-        Err(e) => return (pic_idx, Err(anyhow::anyhow!("{e}"))),
-    };
+    let host = reqwest::Url::parse(&url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let mut probe = probes.get(&host);
+    // ответ на HEAD, который перепроверяется через Range
+    let mut head_status = None;
+    loop {
+        let request = match probe.request(&url) {
+            Ok(r) => r,
+            Err(e) => {
+                return (
+                    pic_idx,
+                    None,
+                    Err(Failure {
+                        kind: FailureKind::Request,
+                        status: None,
+                        url: Some(url),
+                        message: e.to_string(),
+                    }),
+                )
+            }
+        };
 
-    let resp = match client.call(request).await {
-        Ok(r) => r,
-        Err(e) => return (pic_idx, Err(anyhow::anyhow!("url={url} - err={e}"))),
-    };
+        let resp = match client.call(request).await {
+            Ok(r) => r,
+            Err(e) => {
+                return (
+                    pic_idx,
+                    None,
+                    Err(Failure {
+                        kind: failure_kind(&e),
+                        status: None,
+                        url: Some(url),
+                        message: e.to_string(),
+                    }),
+                )
+            }
+        };
 
-    match resp.status() {
-        hyper::StatusCode::OK => {
-            trace!("do_request {url} status_code::ok");
-            (pic_idx, Ok(true))
-        }
-        hyper::StatusCode::NOT_FOUND => {
-            trace!("do_request {url} status_code::not_found");
-            (pic_idx, Ok(false))
-        }
-        _ => {
-            trace!("do_request {url} status_code::отличается от ожидаемого");
-            return (pic_idx, Err(anyhow!("{url} response.status")));
+        let status = resp.status();
+        match (probe, status) {
+            (
+                Probe::Head,
+                hyper::StatusCode::METHOD_NOT_ALLOWED | hyper::StatusCode::NOT_IMPLEMENTED,
+            ) => {
+                probes.reject_head(&host);
+                probe = Probe::Range;
+            }
+            (Probe::Head, status) if status.is_client_error() && !probes.trusts_head(&host) => {
+                trace!("do_request {url} HEAD status {status}, checking with range GET");
+                head_status = Some(status);
+                probe = Probe::Range;
+            }
+            (_, hyper::StatusCode::OK) | (Probe::Range, hyper::StatusCode::PARTIAL_CONTENT) => {
+                trace!("do_request {url} status_code::ok");
+                if let Some(status) = head_status {
+                    warn!("{host} answered HEAD {url} with {status}, but the image exists");
+                    probes.reject_head(&host);
+                }
+                return (pic_idx, Some(status), Ok(true));
+            }
+            (_, hyper::StatusCode::NOT_FOUND) => {
+                trace!("do_request {url} status_code::not_found");
+                if head_status == Some(hyper::StatusCode::NOT_FOUND) {
+                    probes.trust_head(&host);
+                }
+                return (pic_idx, Some(status), Ok(false));
+            }
+            (_, status) => {
+                trace!("do_request {url} status_code::отличается от ожидаемого");
+                return (
+                    pic_idx,
+                    Some(status),
+                    Err(Failure {
+                        kind: FailureKind::Status,
+                        status: Some(status.as_u16() as i16),
+                        url: Some(url),
+                        message: format!("unexpected response.status {status}"),
+                    }),
+                );
+            }
         }
     }
 }
 
-fn generate_urls_by_nm_id(nm_id: &i64, pics_count: &i16) -> Vec<String> {
-    let bucket = nm_id / 10000 * 10000;
-    let mut image_urls = Vec::new();
-    for (t, avif) in SIZES {
-        for i in 1..=*pics_count {
-            image_urls.push(format_url(t, bucket, nm_id, i, JPG));
-        }
+/// разбирает ошибку клиента, чтобы в шарде было видно таймаут это или обрыв соединения
+fn failure_kind(err: &BoxError) -> FailureKind {
+    if err.is::<tower::timeout::error::Elapsed>() {
+        return FailureKind::Timeout;
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_timeout() => FailureKind::Timeout,
+        Some(e) if e.is_connect() => FailureKind::Connect,
+        _ => FailureKind::Request,
     }
-    image_urls
 }
 
 fn format_url(t: &str, bucket: i64, nm_id: &i64, i: i16, ext: &str) -> String {
@@ -350,17 +1431,15 @@ async fn worker_test() {
             .service(service_fn(move |req| svc.execute(req)))
     };
 
-    let nm = Nomenclature {
-        nm_id: 91249210,
-        old_pics_count: 10,
-        new_pics_count: None,
-        good_links: "".to_string(),
-        in_process: false,
-        is_finished: false,
-        retries: 0,
-    };
-
-    let res = worker_fn(nm, cli.clone()).await;
+    let res = worker_fn(
+        &Shard::new("shard_1").unwrap(),
+        &Matrix::default(),
+        &Probes::default(),
+        None,
+        test_nm(91249210, 10),
+        cli.clone(),
+    )
+    .await;
     println!("{:?}", res);
 }
 
@@ -368,3 +1447,493 @@ async fn worker_test() {
 // https://images.wbstatic.net/big/new/64020000/64023641-1.jpg
 // https://images.wbstatic.net/big/new/64020000/64023641-2.jpg
 // https://images.wbstatic.net/big/new/64020000/64023641-3.jpg
+
+/// номенклатура в том виде, в каком ее отдает pull
+#[cfg(test)]
+fn test_nm(nm_id: i64, old_pics_count: i16) -> Nomenclature {
+    Nomenclature {
+        nm_id,
+        old_pics_count,
+        new_pics_count: None,
+        good_links: String::new(),
+        in_process: true,
+        is_finished: false,
+        retries: 0,
+        worker_id: Some("test".to_string()),
+        claimed_at: None,
+        lease_until: None,
+        next_attempt_at: None,
+        error: String::new(),
+        error_kind: None,
+        error_status: None,
+        error_url: None,
+        claim_generation: 1,
+        finished_at: None,
+        rechecks: 0,
+        pics: Vec::new(),
+        pic_variants: sqlx::types::Json(Variants::new()),
+    }
+}
+
+/// cdn, который отвечает на любой запрос статусом из status
+#[cfg(test)]
+fn fake_cdn(
+    status: fn(&Request) -> u16,
+) -> impl Service<
+    Request,
+    Response = Response,
+    Error = BoxError,
+    Future = impl Future<Output = Result<Response, BoxError>> + Send,
+> + Copy
+       + Send
+       + Sync
+       + 'static {
+    service_fn(move |req: Request| {
+        let status = status(&req);
+        async move {
+            let resp = hyper::Response::builder().status(status).body("").unwrap();
+            Ok::<_, BoxError>(Response::from(resp))
+        }
+    })
+}
+
+/// размер, номер картинки и формат из ссылки вида /big/new/0/1-2.jpg
+#[cfg(test)]
+fn test_pic(req: &Request) -> (&str, i16, &str) {
+    let mut path = req.url().path().trim_start_matches('/').split('/');
+    let size = path.next().unwrap();
+    let (name, ext) = path.next_back().unwrap().rsplit_once('.').unwrap();
+    let idx = name.rsplit_once('-').unwrap().1.parse().unwrap();
+    (size, idx, ext)
+}
+
+/// ссылка на картинку i номенклатуры 1 в основном варианте
+#[cfg(test)]
+fn test_url(i: i16) -> String {
+    Matrix::default().url(1, i)
+}
+
+/// настройки для тестов одного шарда, spill у каждого шарда свой
+#[cfg(test)]
+fn test_settings(shard: &Shard) -> Settings {
+    Settings {
+        shards: vec![shard.clone()],
+        concurrency: WORKERS,
+        steal: Steal::Never,
+        worker_id: "test".to_string(),
+        lease: Duration::from_secs(60),
+        reap_interval: Duration::from_secs(60),
+        poll_interval: Duration::from_secs(10),
+        stats_interval: Duration::from_secs(60),
+        heartbeat_interval: Duration::from_secs(15),
+        backoff: Backoff::default(),
+        shard_settings: HashMap::new(),
+        recheck: RecheckPolicy::default(),
+        matrix: Matrix::default(),
+        probes: Probes::default(),
+        discover: None,
+        spill: Spill::new(std::env::temp_dir().join(format!("{shard}.test.spill.ndjson"))),
+        spill_attempts: 2,
+    }
+}
+
+#[tokio::test]
+async fn replay_spill_test() {
+    let queue = InMemoryQueue::new();
+    let shard = Shard::new("shard_replay").unwrap();
+    queue.create_shard(&shard).await.unwrap();
+    queue.seed(&shard, [(1, 3)]).unwrap();
+    let nms = queue
+        .pull(
+            &shard,
+            "test",
+            1,
+            Duration::from_secs(60),
+            &RecheckPolicy::default(),
+        )
+        .await
+        .unwrap();
+
+    let settings = test_settings(&shard);
+    let spill = &settings.spill;
+    let _ = std::fs::remove_file(spill.path());
+    let _ = std::fs::remove_file(spill.failed_path());
+    // шард удалили, пока пачка лежала в spill - очередь ее не примет
+    let gone = Shard::new("shard_gone").unwrap();
+    spill.append(&gone, &Batch::default()).unwrap();
+    let batch = Batch {
+        finished: vec![(
+            nms[0].claim(),
+            3,
+            "a;b;c".to_string(),
+            vec![1, 2, 3],
+            Variants::new(),
+        )],
+        ..Batch::default()
+    };
+    spill.append(&shard, &batch).unwrap();
+
+    replay_spill(&settings, &queue).await.unwrap();
+    assert!(queue.get(&shard, 1).unwrap().is_finished);
+    assert_eq!(
+        vec![gone.clone()],
+        spill
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect::<Vec<_>>()
+    );
+
+    replay_spill(&settings, &queue).await.unwrap();
+    assert!(!spill.path().exists());
+    let failed = Spill::new(spill.failed_path());
+    assert_eq!(vec![(gone, Batch::default())], failed.load().unwrap());
+    std::fs::remove_file(spill.failed_path()).unwrap();
+}
+
+#[tokio::test]
+async fn process_batch_test() {
+    // nm 1 - две картинки из трех, nm 2 - cdn отвечает 503
+    let cli = fake_cdn(|req| match test_pic(req) {
+        _ if req.url().path().contains("/2-") => 503,
+        (_, 1 | 2, _) => 200,
+        _ => 404,
+    });
+
+    let queue = InMemoryQueue::new();
+    let shard = Shard::new("shard_1").unwrap();
+    queue.create_shard(&shard).await.unwrap();
+    queue.seed(&shard, [(1, 3), (2, 3)]).unwrap();
+
+    let settings = test_settings(&shard);
+    assert_eq!(2, process_batch(&settings, &shard, &queue, cli).await);
+
+    let nm = queue.get(&shard, 1).unwrap();
+    assert!(nm.is_finished);
+    assert_eq!(Some(2), nm.new_pics_count);
+    assert_eq!(2, nm.good_links.split(';').count());
+    assert_eq!(vec![1, 2], nm.pics);
+
+    let nm = queue.get(&shard, 2).unwrap();
+    assert!(!nm.is_finished && !nm.in_process);
+    assert_eq!(1, nm.retries);
+    assert_eq!(Some(503), nm.error_status);
+
+    let attempts = queue.attempts(&shard, 1).await.unwrap();
+    assert_eq!(1, attempts.len());
+    assert_eq!(vec![200, 200, 404], attempts[0].statuses);
+    assert_eq!("finished", attempts[0].outcome);
+    let attempts = queue.attempts(&shard, 2).await.unwrap();
+    assert_eq!("failed", attempts[0].outcome);
+    assert!(attempts[0].statuses.contains(&503));
+
+    // второй ретрай отложен backoff'ом
+    assert_eq!(0, process_batch(&settings, &shard, &queue, cli).await);
+}
+
+#[tokio::test]
+async fn extend_lease_test() {
+    // cdn отвечает дольше аренды, соседний воркер забирает все, что она отпустила
+    let cli = service_fn(|_req: Request| async {
+        sleep(Duration::from_millis(300)).await;
+        let resp = hyper::Response::builder().status(200).body("").unwrap();
+        Ok::<_, BoxError>(Response::from(resp))
+    });
+    let queue = InMemoryQueue::new();
+    let shard = Shard::new("shard_lease").unwrap();
+    queue.create_shard(&shard).await.unwrap();
+    queue.seed(&shard, [(1, 1)]).unwrap();
+    let settings = Settings {
+        lease: Duration::from_millis(150),
+        ..test_settings(&shard)
+    };
+
+    let thief = async {
+        loop {
+            sleep(Duration::from_millis(50)).await;
+            queue.reap_expired(&shard).await.unwrap();
+            let stolen = queue
+                .pull(&shard, "thief", 1, settings.lease, &settings.recheck)
+                .await
+                .unwrap();
+            assert!(stolen.is_empty());
+        }
+    };
+    tokio::select! {
+        pulled = process_batch(&settings, &shard, &queue, cli) => assert_eq!(1, pulled),
+        () = thief => (),
+    }
+    let nm = queue.get(&shard, 1).unwrap();
+    assert!(nm.is_finished);
+    assert_eq!(Some(1), nm.new_pics_count);
+}
+
+#[tokio::test]
+async fn steal_test() {
+    // в shard_2 у nm 4 cdn отвечает 503
+    let cli = fake_cdn(|req| {
+        if req.url().path().contains("/4-") {
+            503
+        } else {
+            200
+        }
+    });
+    let queue = InMemoryQueue::new();
+    let shard = Shard::new("shard_1").unwrap();
+    let shard_2 = Shard::new("shard_2").unwrap();
+    queue.create_shard(&shard).await.unwrap();
+    queue.create_shard(&shard_2).await.unwrap();
+    queue.seed(&shard_2, [(3, 1), (4, 1), (5, 1)]).unwrap();
+
+    let mut victim = None;
+    let settings = test_settings(&shard);
+    assert_eq!(0, steal(&settings, &shard, &queue, &mut victim, cli).await);
+    assert_eq!(None, victim);
+
+    // основной шард пуст - пачка забирается из соседнего по его настройкам
+    let victim_settings = ShardSettings {
+        concurrency: 2,
+        backoff: Backoff {
+            base: Duration::from_secs(7200),
+            multiplier: 1.0,
+            jitter: 0.0,
+            cap: Duration::from_secs(7200),
+        },
+    };
+    let settings = Settings {
+        steal: Steal::Busiest,
+        shard_settings: HashMap::from([(shard_2.clone(), victim_settings)]),
+        ..settings
+    };
+    assert_eq!(2, steal(&settings, &shard, &queue, &mut victim, cli).await);
+    assert_eq!(Some(shard_2.clone()), victim);
+    assert!(queue.get(&shard_2, 3).unwrap().is_finished);
+    let retry_at = queue.get(&shard_2, 4).unwrap().next_attempt_at.unwrap();
+    assert!(retry_at > Utc::now() + chrono::Duration::hours(1));
+
+    // донор запоминается, пока в нем есть джобы
+    assert_eq!(1, steal(&settings, &shard, &queue, &mut victim, cli).await);
+    assert!(queue.get(&shard_2, 5).unwrap().is_finished);
+    assert_eq!(0, steal(&settings, &shard, &queue, &mut victim, cli).await);
+}
+
+#[test]
+fn test_matrix_new() {
+    let matrix = Matrix::new("big, TM,", "jpg,webp").unwrap();
+    assert_eq!(
+        vec!["big.jpg", "big.webp", "tm.jpg", "tm.webp"],
+        matrix.variants()
+    );
+    assert!(Matrix::new("", "jpg").is_err());
+    assert!(Matrix::new("big", "../jpg").is_err());
+}
+
+#[tokio::test]
+async fn matrix_variants_test() {
+    // big.jpg есть у обеих картинок, webp - только у первой, tm - только у второй
+    let cli = fake_cdn(|req| match test_pic(req) {
+        (_, 2, "jpg") | ("big", 1, _) => 200,
+        _ => 404,
+    });
+    let matrix = Matrix::new("big,tm", "jpg,webp").unwrap();
+    let (attempt, result) = worker_fn(
+        &Shard::new("shard_1").unwrap(),
+        &matrix,
+        &Probes::default(),
+        None,
+        test_nm(1, 2),
+        cli,
+    )
+    .await;
+    assert_eq!(
+        vec![200, 200, 404, 404, 200, 404, 200, 404],
+        attempt.statuses
+    );
+    let (_, new_pics_count, good_links, pics, variants) = result.unwrap();
+    assert_eq!((2, vec![1, 2]), (new_pics_count, pics));
+    assert!(
+        good_links.ends_with("/big/new/0/1-1.jpg;https://images.wbstatic.net/big/new/0/1-2.jpg")
+    );
+    assert_eq!(
+        Variants::from([
+            (1, vec!["big.jpg".to_string(), "big.webp".to_string()]),
+            (2, vec!["big.jpg".to_string(), "tm.jpg".to_string()]),
+        ]),
+        variants
+    );
+}
+
+#[tokio::test]
+async fn matrix_secondary_failure_test() {
+    // webp отдает 503: картинки по основному варианту все равно засчитываются
+    let cli = fake_cdn(|req| match test_pic(req) {
+        (_, _, "webp") => 503,
+        _ => 200,
+    });
+    let matrix = Matrix::new("big", "jpg,webp").unwrap();
+    let (attempt, result) = worker_fn(
+        &Shard::new("shard_1").unwrap(),
+        &matrix,
+        &Probes::default(),
+        None,
+        test_nm(1, 2),
+        cli,
+    )
+    .await;
+    assert_eq!(vec![200, 503, 200, 503], attempt.statuses);
+    let (_, new_pics_count, _, pics, variants) = result.unwrap();
+    assert_eq!((2, vec![1, 2]), (new_pics_count, pics));
+    assert_eq!(vec!["big.jpg".to_string()], variants[&1]);
+}
+
+#[tokio::test]
+async fn probe_test() {
+    // cdn не принимает HEAD, на Range отвечает 206, целиком картинку не отдает
+    let cli = fake_cdn(|req| {
+        if req.method() == reqwest::Method::HEAD {
+            405
+        } else if !req.headers().contains_key(reqwest::header::RANGE) {
+            500
+        } else if test_pic(req).1 == 1 {
+            206
+        } else {
+            404
+        }
+    });
+    let probes = Probes::default();
+    let (attempt, result) = worker_fn(
+        &Shard::new("shard_1").unwrap(),
+        &Matrix::default(),
+        &probes,
+        None,
+        test_nm(1, 2),
+        cli,
+    )
+    .await;
+    // в попытку пишется ответ на Range, а не на отклоненный HEAD
+    assert_eq!(vec![206, 404], attempt.statuses);
+    assert_eq!(vec![1], result.unwrap().3);
+    assert_eq!(Probe::Range, probes.get("images.wbstatic.net"));
+    assert_eq!(Probe::Head, probes.get("example.com"));
+}
+
+#[tokio::test]
+async fn probe_forbidden_head_test() {
+    // cdn отвечает на HEAD 403, хотя картинка есть
+    let cli = fake_cdn(|req| match (req.method(), test_pic(req).1) {
+        (&reqwest::Method::HEAD, _) => 403,
+        (_, 1) => 206,
+        _ => 404,
+    });
+    let probes = Probes::default();
+    let (_, status, result) = do_request(0, cli, probes.clone(), test_url(1)).await;
+    assert!(result.unwrap());
+    assert_eq!(Some(hyper::StatusCode::PARTIAL_CONTENT), status);
+    assert_eq!(Probe::Range, probes.get("images.wbstatic.net"));
+}
+
+#[tokio::test]
+async fn probe_trusted_head_test() {
+    // 404 на HEAD подтвердился GET'ом: дальше хватает одного HEAD
+    let cli = fake_cdn(|req| match (req.method(), test_pic(req).1) {
+        (&reqwest::Method::HEAD, 2) => 404,
+        (&reqwest::Method::HEAD, _) => 200,
+        (_, 2) => 404,
+        _ => 500,
+    });
+    let probes = Probes::default();
+    let (_, _, result) = do_request(0, cli, probes.clone(), test_url(2)).await;
+    assert!(!result.unwrap());
+    assert!(probes.trusts_head("images.wbstatic.net"));
+    let (_, _, result) = do_request(0, cli, probes.clone(), test_url(1)).await;
+    assert!(result.unwrap());
+}
+
+#[tokio::test]
+async fn discover_test() {
+    let exists = |last: i16| {
+        move |idx: i16| {
+            let status = if idx <= last {
+                hyper::StatusCode::OK
+            } else {
+                hyper::StatusCode::NOT_FOUND
+            };
+            future::ready((0, Some(status), Ok(idx <= last)))
+        }
+    };
+    let mut statuses = Vec::new();
+    assert_eq!(
+        13,
+        discover_last_pic(3, 30, exists(13), &mut statuses)
+            .await
+            .unwrap()
+    );
+    // 4, 6, 10, 18 и бинарный поиск 14, 12, 13
+    assert_eq!(vec![200, 200, 200, 404, 404, 200, 200], statuses);
+    assert_eq!(
+        10,
+        discover_last_pic(3, 10, exists(13), &mut Vec::new())
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        3,
+        discover_last_pic(3, 30, exists(3), &mut Vec::new())
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        0,
+        discover_last_pic(0, 30, exists(0), &mut Vec::new())
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn discover_worker_test() {
+    // в карточке 5 картинок, а известно про 2
+    let cli = fake_cdn(|req| if test_pic(req).1 <= 5 { 200 } else { 404 });
+    let (_, result) = worker_fn(
+        &Shard::new("shard_1").unwrap(),
+        &Matrix::default(),
+        &Probes::default(),
+        Some(20),
+        test_nm(1, 2),
+        cli,
+    )
+    .await;
+    let (_, new_pics_count, good_links, pics, _) = result.unwrap();
+    assert_eq!((5, vec![1, 2, 3, 4, 5]), (new_pics_count, pics));
+    assert_eq!(5, good_links.split(';').count());
+}
+
+#[test]
+fn test_shard_backoff() {
+    // суффиксы уникальны для теста, общие BACKOFF_* не трогаются
+    std::env::set_var("BACKOFF_BASE_SECS_BACKOFF_FAST", "1");
+    std::env::set_var("BACKOFF_JITTER_BACKOFF_FAST", "0");
+    std::env::set_var("BACKOFF_BASE_SECS_BACKOFF_SLOW", "120");
+    std::env::set_var("BACKOFF_JITTER_BACKOFF_SLOW", "0");
+    let fast = Shard::new("backoff_fast").unwrap();
+    let slow = Shard::new("backoff_slow").unwrap();
+    let other = Shard::new("backoff_other").unwrap();
+
+    let settings = Settings {
+        shard_settings: [&fast, &slow]
+            .into_iter()
+            .map(|shard| (shard.clone(), shard_settings_from_env(shard).unwrap()))
+            .collect(),
+        ..test_settings(&fast)
+    };
+    assert_eq!(
+        Duration::from_secs(1),
+        settings.shard(&fast).backoff.delay(0)
+    );
+    assert_eq!(
+        Duration::from_secs(120),
+        settings.shard(&slow).backoff.delay(0)
+    );
+    assert_eq!(settings.backoff, settings.shard(&other).backoff);
+}
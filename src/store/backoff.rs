@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// политика отложенного повтора для упавших джоб:
+/// base * multiplier^retries, но не больше cap, плюс-минус jitter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    /// задержка после первой ошибки
+    pub base: Duration,
+    /// во сколько раз растет задержка с каждой следующей ошибкой
+    pub multiplier: f64,
+    /// доля случайного разброса задержки, 0.2 дает +-20%
+    pub jitter: f64,
+    /// верхняя граница задержки до учета разброса
+    pub cap: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(30),
+            multiplier: 4.0,
+            jitter: 0.2,
+            cap: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl Backoff {
+    /// задержка до следующей попытки для джобы, у которой уже было `retries` ошибок
+    pub fn delay(&self, retries: i64) -> Duration {
+        let exp = retries.clamp(0, i32::MAX as i64) as i32;
+        let delay =
+            (self.base.as_secs_f64() * self.multiplier.powi(exp)).min(self.cap.as_secs_f64());
+        let spread = 1.0 + self.jitter * (2.0 * fastrand::f64() - 1.0);
+        Duration::from_secs_f64((delay * spread).max(0.0))
+    }
+}
+
+#[test]
+fn test_backoff_delay() {
+    let backoff = Backoff {
+        base: Duration::from_secs(10),
+        multiplier: 2.0,
+        jitter: 0.0,
+        cap: Duration::from_secs(60),
+    };
+    assert_eq!(Duration::from_secs(10), backoff.delay(0));
+    assert_eq!(Duration::from_secs(20), backoff.delay(1));
+    assert_eq!(Duration::from_secs(40), backoff.delay(2));
+    assert_eq!(Duration::from_secs(60), backoff.delay(3));
+    assert_eq!(Duration::from_secs(60), backoff.delay(i64::MAX));
+
+    let backoff = Backoff {
+        jitter: 0.5,
+        ..backoff
+    };
+    for _ in 0..100 {
+        let delay = backoff.delay(1);
+        assert!(delay >= Duration::from_secs(10) && delay <= Duration::from_secs(30));
+    }
+}
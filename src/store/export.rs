@@ -0,0 +1,111 @@
+use {
+    crate::store::shard::Shard,
+    serde::Serialize,
+    std::{fmt, str::FromStr},
+};
+
+/// какие номенклатуры выгружать из шарда
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportStatus {
+    /// проверенные номенклатуры
+    Finished,
+    /// исчерпавшие ретраи, лежат в dead_letter
+    Failed,
+    /// проверенные, у которых картинок стало меньше, чем было
+    Regressed,
+    /// проверенные, у которых после пропуска нашлись еще картинки
+    Gaps,
+}
+
+impl FromStr for ExportStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "finished" => Ok(ExportStatus::Finished),
+            "failed" => Ok(ExportStatus::Failed),
+            "regressed" => Ok(ExportStatus::Regressed),
+            "gaps" => Ok(ExportStatus::Gaps),
+            _ => anyhow::bail!(
+                "unknown export status [{s}], expected finished|failed|regressed|gaps"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ExportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportStatus::Finished => "finished",
+            ExportStatus::Failed => "failed",
+            ExportStatus::Regressed => "regressed",
+            ExportStatus::Gaps => "gaps",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ExportRow {
+    pub shard: String,
+    pub nm_id: i64,
+    pub old_pics_count: i16,
+    pub new_pics_count: Option<i16>,
+    pub good_links: String,
+    /// номера ненайденных картинок через `;`, их и нужно перерисовать
+    pub missing_pics: String,
+    pub error: String,
+}
+
+/// колонки выгрузки из шарда
+const SHARD_COLUMNS: &str = "$1::text as shard, nm_id, old_pics_count, new_pics_count, good_links,
+    array_to_string(array(
+        select i from generate_series(1, old_pics_count) as i where i <> all(pics)
+    ), ';') as missing_pics,
+    error";
+
+/// запрос выгрузки для шарда, $1 - имя шарда; строки отсортированы по nm_id
+pub fn export_query(shard: &Shard, status: ExportStatus) -> String {
+    match status {
+        ExportStatus::Finished => format!(
+            "select {SHARD_COLUMNS}
+            from {table}
+            where is_finished = true
+            order by nm_id",
+            table = shard.ident()
+        ),
+        ExportStatus::Regressed => format!(
+            "select {SHARD_COLUMNS}
+            from {table}
+            where is_finished = true and new_pics_count < old_pics_count
+            order by nm_id",
+            table = shard.ident()
+        ),
+        ExportStatus::Gaps => format!(
+            "select {SHARD_COLUMNS}
+            from {table}
+            where is_finished = true and cardinality(pics) > coalesce(new_pics_count, 0)
+            order by nm_id",
+            table = shard.ident()
+        ),
+        ExportStatus::Failed => String::from(
+            "select shard, nm_id, old_pics_count, null::int2 as new_pics_count, ''::text as good_links,
+                ''::text as missing_pics, error
+            from dead_letter
+            where shard = $1
+            order by nm_id",
+        ),
+    }
+}
+
+#[test]
+fn test_export_status() {
+    for status in [
+        ExportStatus::Finished,
+        ExportStatus::Failed,
+        ExportStatus::Regressed,
+        ExportStatus::Gaps,
+    ] {
+        assert_eq!(status, status.to_string().parse().unwrap());
+    }
+    assert!("done".parse::<ExportStatus>().is_err());
+}
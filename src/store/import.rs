@@ -0,0 +1,117 @@
+use {
+    crate::store::shard::Shard,
+    sqlx::{Pool, Postgres},
+    std::{fmt::Write, io::Read},
+};
+
+/// читает пары nm_id,old_pics_count из csv, заголовок определяется по первой строке
+pub fn read_rows<R: Read>(input: R) -> impl Iterator<Item = anyhow::Result<(i64, i16)>> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input)
+        .into_records()
+        .enumerate()
+        .filter_map(|(line, record)| {
+            let row = record
+                .map_err(anyhow::Error::from)
+                .and_then(|record| parse_row(&record));
+            match row {
+                Err(_) if line == 0 => None,
+                row => Some(row.map_err(|err| anyhow::anyhow!("line {}: {err}", line + 1))),
+            }
+        })
+}
+
+pub fn parse_row(record: &csv::StringRecord) -> anyhow::Result<(i64, i16)> {
+    let nm_id = record
+        .get(0)
+        .ok_or_else(|| anyhow::anyhow!("nm_id is missing"))?
+        .parse::<i64>()?;
+    let old_pics_count = record
+        .get(1)
+        .ok_or_else(|| anyhow::anyhow!("old_pics_count is missing"))?
+        .parse::<i16>()?;
+    Ok((nm_id, old_pics_count))
+}
+
+/// номер шарда для nm_id из `shards` штук; не зависит от версии компилятора и платформы,
+/// поэтому повторный импорт того же файла раскладывает строки по тем же таблицам
+pub fn shard_index(nm_id: i64, shards: usize) -> usize {
+    // финализатор splitmix64, чтобы соседние nm_id расходились по разным шардам
+    let mut x = nm_id as u64;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    (x % shards as u64) as usize
+}
+
+/// заливает строки (nm_id, old_pics_count) в шард через COPY во временную таблицу,
+/// уже существующие nm_id пропускаются; возвращает количество вставленных строк
+pub async fn copy_into_shard(
+    client: &Pool<Postgres>,
+    shard: &Shard,
+    rows: &[(i64, i16)],
+) -> anyhow::Result<u64> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let mut data = String::with_capacity(rows.len() * 16);
+    for (nm_id, old_pics_count) in rows {
+        writeln!(data, "{nm_id}\t{old_pics_count}")?;
+    }
+
+    let mut tx = client.begin().await?;
+    sqlx::query(
+        "create temp table if not exists import_staging
+        (
+            nm_id int8 NOT NULL,
+            old_pics_count int2 NOT NULL
+        ) on commit delete rows",
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let mut copy = tx
+        .copy_in_raw("copy import_staging (nm_id, old_pics_count) from stdin")
+        .await?;
+    copy.send(data.into_bytes()).await?;
+    copy.finish().await?;
+
+    let inserted = sqlx::query(&format!(
+        "insert into {table} (nm_id, old_pics_count)
+        select nm_id, old_pics_count from import_staging
+        on conflict (nm_id) do nothing",
+        table = shard.ident()
+    ))
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok(inserted)
+}
+
+#[test]
+fn test_shard_index() {
+    assert_eq!(shard_index(91249210, 10), shard_index(91249210, 10));
+    assert_eq!(0, shard_index(64023641, 1));
+
+    let mut counts = [0; 10];
+    for nm_id in 1..100_000 {
+        counts[shard_index(nm_id, 10)] += 1;
+    }
+    for count in counts {
+        assert!((9_000..11_000).contains(&count), "{counts:?}");
+    }
+}
+
+#[test]
+fn test_read_rows() {
+    let input = "nm_id,old_pics_count\n91249210,10\n64023641, 3\nbad\n";
+    let rows: Vec<_> = read_rows(input.as_bytes()).collect();
+    assert_eq!(3, rows.len());
+    assert_eq!((91249210, 10), *rows[0].as_ref().unwrap());
+    assert_eq!((64023641, 3), *rows[1].as_ref().unwrap());
+    assert!(rows[2].is_err());
+}
@@ -0,0 +1,1115 @@
+use {
+    crate::store::{
+        backoff::Backoff,
+        models::{
+            Attempt, Batch, Claim, DeadLetter, Failure, JobFilter, Nomenclature, Recheck,
+            ShardStats, Variants, Worker,
+        },
+        recheck::RecheckPolicy,
+        shard::Shard,
+        signals::JobSignals,
+        sqlx_queue::{Queue, RETRIES},
+    },
+    async_trait::async_trait,
+    chrono::{DateTime, Utc},
+    sqlx::types::Json,
+    std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex, MutexGuard},
+        time::Duration,
+    },
+};
+
+/// очередь в памяти с той же семантикой аренды, ретраев и dead_letter, что и SqlxPool;
+/// для тестов и локального запуска без postgres
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryQueue {
+    state: Arc<Mutex<State>>,
+    jobs: JobSignals,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    shards: HashMap<String, BTreeMap<i64, Row>>,
+    dead_letters: HashMap<String, BTreeMap<i64, DeadLetter>>,
+    attempts: Vec<Attempt>,
+    rechecks: Vec<Recheck>,
+    workers: BTreeMap<String, Worker>,
+}
+
+#[derive(Debug, Clone)]
+struct Row {
+    nm: Nomenclature,
+    failures: Vec<serde_json::Value>,
+}
+
+impl Row {
+    fn new(nm_id: i64, old_pics_count: i16, failures: Vec<serde_json::Value>) -> Self {
+        Self {
+            nm: Nomenclature {
+                nm_id,
+                old_pics_count,
+                new_pics_count: None,
+                good_links: String::new(),
+                in_process: false,
+                is_finished: false,
+                retries: 0,
+                worker_id: None,
+                claimed_at: None,
+                lease_until: None,
+                next_attempt_at: None,
+                error: String::new(),
+                error_kind: None,
+                error_status: None,
+                error_url: None,
+                claim_generation: 0,
+                finished_at: None,
+                rechecks: 0,
+                pics: Vec::new(),
+                pic_variants: Json(Variants::new()),
+            },
+            failures,
+        }
+    }
+}
+
+impl State {
+    fn shard(&mut self, shard: &Shard) -> anyhow::Result<&mut BTreeMap<i64, Row>> {
+        self.shards
+            .get_mut(shard.as_str())
+            .ok_or_else(|| anyhow::anyhow!("shard [{shard}] does not exist"))
+    }
+
+    /// возвращает потерянные захваты
+    fn finish_jobs(
+        &mut self,
+        shard: &Shard,
+        nms: Vec<(Claim, i16, String, Vec<i16>, Variants)>,
+    ) -> anyhow::Result<Vec<Claim>> {
+        let State {
+            shards, rechecks, ..
+        } = self;
+        let table = shards
+            .get_mut(shard.as_str())
+            .ok_or_else(|| anyhow::anyhow!("shard [{shard}] does not exist"))?;
+        let mut lost = Vec::new();
+        for (claim, new_pics_count, good_links, pics, variants) in nms {
+            let row = match table.get_mut(&claim.nm_id) {
+                Some(row) if owns(row, &claim) => row,
+                _ => {
+                    lost.push(claim);
+                    continue;
+                }
+            };
+            let previous_pics_count = row.nm.new_pics_count;
+            finish(row, Some((new_pics_count, good_links, pics, variants)));
+            if row.nm.rechecks > 0 {
+                rechecks.push(Recheck {
+                    shard: shard.to_string(),
+                    nm_id: row.nm.nm_id,
+                    checked_at: Utc::now(),
+                    previous_pics_count,
+                    new_pics_count,
+                    good_links: row.nm.good_links.clone(),
+                });
+            }
+        }
+        Ok(lost)
+    }
+
+    /// возвращает потерянные захваты
+    fn fail_jobs(
+        &mut self,
+        shard: &Shard,
+        nms: Vec<(Claim, Failure)>,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>> {
+        let now = Utc::now();
+        let table = self.shard(shard)?;
+        let mut exhausted = Vec::new();
+        let mut lost = Vec::new();
+        for (claim, failure) in nms {
+            let nm_id = claim.nm_id;
+            let row = match table.get_mut(&nm_id) {
+                Some(row) if owns(row, &claim) => row,
+                _ => {
+                    lost.push(claim);
+                    continue;
+                }
+            };
+            row.failures.push(serde_json::json!({
+                "at": now.to_rfc3339(),
+                "worker_id": row.nm.worker_id,
+                "kind": failure.kind.as_str(),
+                "status": failure.status,
+                "url": failure.url,
+                "error": failure.message,
+            }));
+            row.nm.in_process = false;
+            row.nm.lease_until = None;
+            row.nm.next_attempt_at = Some(after(now, backoff.delay(row.nm.retries)));
+            row.nm.retries += 1;
+            row.nm.claim_generation += 1;
+            row.nm.error = failure.message;
+            row.nm.error_kind = Some(failure.kind.as_str().to_string());
+            row.nm.error_status = failure.status;
+            row.nm.error_url = failure.url;
+            if row.nm.retries >= RETRIES && row.nm.finished_at.is_some() {
+                // упавшая перепроверка: прежний результат остается в силе
+                row.nm.is_finished = true;
+                row.nm.retries = 0;
+            } else if row.nm.retries >= RETRIES {
+                exhausted.push(nm_id);
+            }
+        }
+
+        let buried: Vec<Row> = exhausted
+            .into_iter()
+            .filter_map(|nm_id| table.remove(&nm_id))
+            .collect();
+        let dead_letters = self.dead_letters.entry(shard.to_string()).or_default();
+        for Row { nm, failures } in buried {
+            dead_letters.insert(
+                nm.nm_id,
+                DeadLetter {
+                    shard: shard.to_string(),
+                    nm_id: nm.nm_id,
+                    old_pics_count: nm.old_pics_count,
+                    retries: nm.retries,
+                    error: nm.error,
+                    error_kind: nm.error_kind,
+                    error_status: nm.error_status,
+                    error_url: nm.error_url,
+                    history: serde_json::Value::Array(failures),
+                    worker_id: nm.worker_id,
+                    dead_at: now,
+                },
+            );
+        }
+        Ok(lost)
+    }
+}
+
+impl InMemoryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// добавляет номенклатуры в шард, уже существующие nm_id пропускаются;
+    /// возвращает количество добавленных
+    pub fn seed(
+        &self,
+        shard: &Shard,
+        rows: impl IntoIterator<Item = (i64, i16)>,
+    ) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        let table = state.shard(shard)?;
+        let mut inserted = 0;
+        for (nm_id, old_pics_count) in rows {
+            if let std::collections::btree_map::Entry::Vacant(e) = table.entry(nm_id) {
+                e.insert(Row::new(nm_id, old_pics_count, Vec::new()));
+                inserted += 1;
+            }
+        }
+        if inserted > 0 {
+            self.jobs.notify(shard.as_str());
+        }
+        Ok(inserted)
+    }
+
+    /// текущее состояние номенклатуры в шарде
+    pub fn get(&self, shard: &Shard, nm_id: i64) -> Option<Nomenclature> {
+        let state = self.lock();
+        state
+            .shards
+            .get(shard.as_str())
+            .and_then(|table| table.get(&nm_id))
+            .map(|row| row.nm.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // паника в другом потоке не портит состояние сильнее, чем упавший запрос в postgres
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// меняет подходящие под filter джобы под одной блокировкой, как транзакция в SqlxPool
+    fn update_where(
+        &self,
+        shard: &Shard,
+        filter: &JobFilter,
+        update: impl Fn(&mut Nomenclature),
+    ) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        let mut updated = 0;
+        for row in state.shard(shard)?.values_mut() {
+            if matches(filter, &row.nm) {
+                update(&mut row.nm);
+                updated += 1;
+            }
+        }
+        if updated > 0 {
+            self.jobs.notify(shard.as_str());
+        }
+        Ok(updated)
+    }
+}
+
+fn after(now: DateTime<Utc>, delay: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(delay)
+        .ok()
+        .and_then(|delay| now.checked_add_signed(delay))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+#[async_trait]
+impl Queue for InMemoryQueue {
+    async fn pull(
+        &self,
+        shard: &Shard,
+        worker_id: &str,
+        jobs: i64,
+        lease: Duration,
+        recheck: &RecheckPolicy,
+    ) -> anyhow::Result<Vec<Nomenclature>> {
+        let now = Utc::now();
+        let mut state = self.lock();
+        let table = state.shard(shard)?;
+        let claim = |row: &mut Row| {
+            row.nm.in_process = true;
+            row.nm.worker_id = Some(worker_id.to_string());
+            row.nm.claim_generation += 1;
+            row.nm.claimed_at = Some(now);
+            row.nm.lease_until = Some(after(now, lease));
+            row.nm.clone()
+        };
+        let result: Vec<Nomenclature> = table
+            .values_mut()
+            .filter(|row| pullable(&row.nm, now))
+            .take(jobs.max(0) as usize)
+            .map(claim)
+            .collect();
+        if !result.is_empty() {
+            return Ok(result);
+        }
+        let result = table
+            .values_mut()
+            .filter(|row| recheck.due(&row.nm, now))
+            .take(jobs.max(0) as usize)
+            .map(|row| {
+                row.nm.is_finished = false;
+                row.nm.retries = 0;
+                row.nm.next_attempt_at = None;
+                row.nm.rechecks += 1;
+                claim(row)
+            })
+            .collect();
+        Ok(result)
+    }
+
+    async fn extend_lease(
+        &self,
+        shard: &Shard,
+        claims: &[Claim],
+        lease: Duration,
+    ) -> anyhow::Result<u64> {
+        let lease_until = after(Utc::now(), lease);
+        let mut state = self.lock();
+        let table = state.shard(shard)?;
+        let mut extended = 0;
+        for claim in claims {
+            if let Some(row) = table.get_mut(&claim.nm_id) {
+                if row.nm.in_process && owns(row, claim) {
+                    row.nm.lease_until = Some(lease_until);
+                    extended += 1;
+                }
+            }
+        }
+        Ok(extended)
+    }
+
+    async fn reap_expired(&self, shard: &Shard) -> anyhow::Result<u64> {
+        let now = Utc::now();
+        let mut state = self.lock();
+        let mut reaped = 0;
+        for row in state.shard(shard)?.values_mut() {
+            if row.nm.in_process && row.nm.lease_until.is_none_or(|at| at < now) {
+                row.nm.in_process = false;
+                row.nm.lease_until = None;
+                reaped += 1;
+            }
+        }
+        if reaped > 0 {
+            self.jobs.notify(shard.as_str());
+        }
+        Ok(reaped)
+    }
+
+    async fn release_claims(&self, shard: &Shard, worker_id: &str) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        let mut released = 0;
+        for row in state.shard(shard)?.values_mut() {
+            if row.nm.in_process && row.nm.worker_id.as_deref() == Some(worker_id) {
+                row.nm.in_process = false;
+                row.nm.lease_until = None;
+                row.nm.worker_id = None;
+                row.nm.claim_generation += 1;
+                released += 1;
+            }
+        }
+        if released > 0 {
+            self.jobs.notify(shard.as_str());
+        }
+        Ok(released)
+    }
+
+    async fn reap_dead_workers(&self, shard: &Shard, stale_after: Duration) -> anyhow::Result<u64> {
+        let now = Utc::now();
+        let mut state = self.lock();
+        let dead: Vec<String> = state
+            .workers
+            .values()
+            .filter(|worker| after(worker.heartbeat_at, stale_after) < now)
+            .map(|worker| worker.worker_id.clone())
+            .collect();
+        let mut reaped = 0;
+        for row in state.shard(shard)?.values_mut() {
+            if row.nm.in_process
+                && row
+                    .nm
+                    .worker_id
+                    .as_ref()
+                    .is_some_and(|id| dead.contains(id))
+            {
+                row.nm.in_process = false;
+                row.nm.lease_until = None;
+                reaped += 1;
+            }
+        }
+        if reaped > 0 {
+            self.jobs.notify(shard.as_str());
+        }
+        Ok(reaped)
+    }
+
+    async fn finish_job(&self, shard: &Shard, claim: &Claim) -> anyhow::Result<bool> {
+        let mut state = self.lock();
+        match state.shard(shard)?.get_mut(&claim.nm_id) {
+            Some(row) if owns(row, claim) => {
+                finish(row, None);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn fail_job(
+        &self,
+        shard: &Shard,
+        claim: &Claim,
+        failure: Failure,
+        backoff: &Backoff,
+    ) -> anyhow::Result<bool> {
+        let lost = self
+            .batch_fail_jobs(shard, vec![(claim.clone(), failure)], backoff)
+            .await?;
+        Ok(lost.is_empty())
+    }
+
+    async fn clear(&self, shard: &Shard) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        Ok(std::mem::take(state.shard(shard)?).len() as u64)
+    }
+
+    async fn requeue(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64> {
+        self.update_where(shard, filter, |nm| {
+            nm.in_process = false;
+            nm.is_finished = false;
+            nm.worker_id = None;
+            nm.claimed_at = None;
+            nm.lease_until = None;
+            nm.next_attempt_at = None;
+            nm.finished_at = None;
+        })
+    }
+
+    async fn reset_retries(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64> {
+        self.update_where(shard, filter, |nm| {
+            nm.retries = 0;
+            nm.next_attempt_at = None;
+            nm.error = String::new();
+            nm.error_kind = None;
+            nm.error_status = None;
+            nm.error_url = None;
+        })
+    }
+
+    async fn clear_results(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64> {
+        self.update_where(shard, filter, |nm| {
+            nm.new_pics_count = None;
+            nm.good_links = String::new();
+            nm.pics.clear();
+            nm.pic_variants.clear();
+            nm.finished_at = None;
+        })
+    }
+
+    async fn batch_finish_jobs(
+        &self,
+        shard: &Shard,
+        nms: Vec<(Claim, i16, String, Vec<i16>, Variants)>,
+    ) -> anyhow::Result<Vec<Claim>> {
+        self.lock().finish_jobs(shard, nms)
+    }
+
+    async fn batch_fail_jobs(
+        &self,
+        shard: &Shard,
+        nms: Vec<(Claim, Failure)>,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>> {
+        self.lock().fail_jobs(shard, nms, backoff)
+    }
+
+    async fn commit_batch(
+        &self,
+        shard: &Shard,
+        batch: &Batch,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>> {
+        let mut state = self.lock();
+        let mut lost = state.finish_jobs(shard, batch.finished.clone())?;
+        lost.extend(state.fail_jobs(shard, batch.failed.clone(), backoff)?);
+        state
+            .attempts
+            .extend(batch.attempts.iter().map(|attempt| Attempt {
+                shard: shard.to_string(),
+                ..attempt.clone()
+            }));
+        Ok(lost)
+    }
+
+    async fn attempts(&self, shard: &Shard, nm_id: i64) -> anyhow::Result<Vec<Attempt>> {
+        let mut result: Vec<Attempt> = self
+            .lock()
+            .attempts
+            .iter()
+            .filter(|attempt| attempt.shard == shard.as_str() && attempt.nm_id == nm_id)
+            .cloned()
+            .collect();
+        result.sort_by_key(|attempt| attempt.started_at);
+        Ok(result)
+    }
+
+    async fn rechecks(&self, shard: &Shard, nm_id: i64) -> anyhow::Result<Vec<Recheck>> {
+        Ok(self
+            .lock()
+            .rechecks
+            .iter()
+            .filter(|recheck| recheck.shard == shard.as_str() && recheck.nm_id == nm_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn dead_letters(
+        &self,
+        shard: &Shard,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<DeadLetter>> {
+        let state = self.lock();
+        let mut result: Vec<DeadLetter> = state
+            .dead_letters
+            .get(shard.as_str())
+            .map(|dead| dead.values().cloned().collect())
+            .unwrap_or_default();
+        result.sort_by(|a, b| b.dead_at.cmp(&a.dead_at).then(a.nm_id.cmp(&b.nm_id)));
+        Ok(result
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn requeue_dead_letters(&self, shard: &Shard, nm_ids: Vec<i64>) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        let State {
+            shards,
+            dead_letters,
+            ..
+        } = &mut *state;
+        let table = shards
+            .get_mut(shard.as_str())
+            .ok_or_else(|| anyhow::anyhow!("shard [{shard}] does not exist"))?;
+        let dead = match dead_letters.get_mut(shard.as_str()) {
+            Some(dead) => dead,
+            None => return Ok(0),
+        };
+        let mut requeued = 0;
+        for nm_id in nm_ids {
+            // номенклатура уже есть в шарде - письмо с историей остается в dead_letter
+            if table.contains_key(&nm_id) {
+                continue;
+            }
+            let letter = match dead.remove(&nm_id) {
+                Some(letter) => letter,
+                None => continue,
+            };
+            let history = match letter.history {
+                serde_json::Value::Array(history) => history,
+                _ => Vec::new(),
+            };
+            table.insert(nm_id, Row::new(nm_id, letter.old_pics_count, history));
+            requeued += 1;
+        }
+        if requeued > 0 {
+            self.jobs.notify(shard.as_str());
+        }
+        Ok(requeued)
+    }
+
+    async fn purge_dead_letters(
+        &self,
+        shard: &Shard,
+        nm_ids: Option<Vec<i64>>,
+    ) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        let dead = match state.dead_letters.get_mut(shard.as_str()) {
+            Some(dead) => dead,
+            None => return Ok(0),
+        };
+        let purged = match nm_ids {
+            Some(nm_ids) => nm_ids
+                .into_iter()
+                .filter(|nm_id| dead.remove(nm_id).is_some())
+                .count(),
+            None => std::mem::take(dead).len(),
+        };
+        Ok(purged as u64)
+    }
+
+    async fn shards(&self) -> anyhow::Result<Vec<Shard>> {
+        let mut shards = self
+            .lock()
+            .shards
+            .keys()
+            .map(|name| Shard::new(name))
+            .collect::<anyhow::Result<Vec<Shard>>>()?;
+        shards.sort_by_key(|shard| (shard.as_str().len(), shard.to_string()));
+        Ok(shards)
+    }
+
+    async fn shard(&self, name: &str) -> anyhow::Result<Shard> {
+        let shard = Shard::new(name)?;
+        if !self.lock().shards.contains_key(shard.as_str()) {
+            anyhow::bail!("shard [{shard}] does not exist");
+        }
+        Ok(shard)
+    }
+
+    async fn create_shard(&self, shard: &Shard) -> anyhow::Result<()> {
+        self.lock().shards.entry(shard.to_string()).or_default();
+        Ok(())
+    }
+
+    async fn wait_for_jobs(&self, shard: &Shard, timeout: Duration) -> anyhow::Result<bool> {
+        Ok(self.jobs.wait(shard.as_str(), timeout).await)
+    }
+
+    async fn stats(&self, shard: &Shard) -> anyhow::Result<ShardStats> {
+        let mut state = self.lock();
+        let exhausted = state
+            .dead_letters
+            .get(shard.as_str())
+            .map_or(0, |dead| dead.len()) as i64;
+        let mut stats = ShardStats {
+            exhausted,
+            total: exhausted,
+            ..ShardStats::default()
+        };
+        for Row { nm, .. } in state.shard(shard)?.values() {
+            stats.total += 1;
+            if nm.in_process {
+                stats.in_process += 1;
+            } else if nm.is_finished {
+                stats.finished += 1;
+                if nm.new_pics_count.is_some_and(|new| new < nm.old_pics_count) {
+                    stats.regressed += 1;
+                }
+            } else if nm.retries > 0 {
+                stats.failed_retrying += 1;
+            } else {
+                stats.pending += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn busiest_shard(&self, except: &[Shard]) -> anyhow::Result<Option<Shard>> {
+        let now = Utc::now();
+        let state = self.lock();
+        let busiest = state
+            .shards
+            .iter()
+            .filter(|(name, _)| !except.iter().any(|shard| shard.as_str() == name.as_str()))
+            .map(|(name, table)| {
+                let pending = table.values().filter(|row| pullable(&row.nm, now)).count();
+                (pending, name)
+            })
+            .filter(|(pending, _)| *pending > 0)
+            .max_by_key(|(pending, _)| *pending);
+        busiest.map(|(_, name)| Shard::new(name)).transpose()
+    }
+
+    async fn heartbeat(&self, worker: &Worker) -> anyhow::Result<()> {
+        self.lock().workers.insert(
+            worker.worker_id.clone(),
+            Worker {
+                heartbeat_at: Utc::now(),
+                ..worker.clone()
+            },
+        );
+        Ok(())
+    }
+
+    async fn deregister_worker(&self, worker_id: &str) -> anyhow::Result<()> {
+        self.lock().workers.remove(worker_id);
+        Ok(())
+    }
+
+    async fn workers(&self) -> anyhow::Result<Vec<Worker>> {
+        Ok(self.lock().workers.values().cloned().collect())
+    }
+}
+
+/// джобу можно забрать прямо сейчас
+fn pullable(nm: &Nomenclature, now: DateTime<Utc>) -> bool {
+    !nm.in_process
+        && !nm.is_finished
+        && nm.retries < RETRIES
+        && nm.next_attempt_at.is_none_or(|at| at <= now)
+}
+
+fn matches(filter: &JobFilter, nm: &Nomenclature) -> bool {
+    match filter {
+        JobFilter::Stuck => nm.in_process,
+        JobFilter::ZeroPics => nm.is_finished && nm.new_pics_count == Some(0),
+        JobFilter::Failed => !nm.is_finished && nm.retries > 0,
+        JobFilter::NmIds(nm_ids) => nm_ids.contains(&nm.nm_id),
+        JobFilter::Regressed => {
+            nm.is_finished && nm.new_pics_count.is_some_and(|new| new < nm.old_pics_count)
+        }
+        JobFilter::Gaps => {
+            nm.is_finished && nm.pics.len() > nm.new_pics_count.unwrap_or(0) as usize
+        }
+    }
+}
+
+/// захват все еще принадлежит воркеру: джобу не отдали другому и не сбросили
+fn owns(row: &Row, claim: &Claim) -> bool {
+    row.nm.worker_id.as_deref() == Some(claim.worker_id.as_str())
+        && row.nm.claim_generation == claim.generation
+}
+
+fn finish(row: &mut Row, result: Option<(i16, String, Vec<i16>, Variants)>) {
+    row.nm.in_process = false;
+    row.nm.claim_generation += 1;
+    row.nm.lease_until = None;
+    row.nm.next_attempt_at = None;
+    row.nm.is_finished = true;
+    row.nm.finished_at = Some(Utc::now());
+    row.nm.error = String::new();
+    row.nm.error_kind = None;
+    row.nm.error_status = None;
+    row.nm.error_url = None;
+    if let Some((new_pics_count, good_links, pics, variants)) = result {
+        row.nm.new_pics_count = Some(new_pics_count);
+        row.nm.good_links = good_links;
+        row.nm.pics = pics;
+        row.nm.pic_variants = Json(variants);
+    }
+}
+
+/// очередь с одним шардом shard_1 и номенклатурами nm_id, old_pics_count
+#[cfg(test)]
+async fn test_queue(rows: &[(i64, i16)]) -> (InMemoryQueue, Shard) {
+    let queue = InMemoryQueue::new();
+    let shard = Shard::new("shard_1").unwrap();
+    queue.create_shard(&shard).await.unwrap();
+    queue.seed(&shard, rows.iter().copied()).unwrap();
+    (queue, shard)
+}
+
+#[cfg(test)]
+fn test_failure() -> Failure {
+    Failure {
+        kind: crate::store::models::FailureKind::Status,
+        status: Some(503),
+        url: None,
+        message: "unexpected response.status".to_string(),
+    }
+}
+
+/// backoff без задержки, упавшую джобу можно сразу забрать снова
+#[cfg(test)]
+fn no_backoff() -> Backoff {
+    Backoff {
+        base: Duration::ZERO,
+        multiplier: 1.0,
+        jitter: 0.0,
+        cap: Duration::ZERO,
+    }
+}
+
+#[cfg(test)]
+const LEASE: Duration = Duration::from_secs(60);
+
+/// забирает джобу и валит ее, пока не кончатся ретраи
+#[cfg(test)]
+async fn exhaust(queue: &InMemoryQueue, shard: &Shard, recheck: &RecheckPolicy) {
+    for _ in 0..RETRIES {
+        let nms = queue.pull(shard, "w1", 1, LEASE, recheck).await.unwrap();
+        assert_eq!(1, nms.len());
+        queue
+            .batch_fail_jobs(shard, vec![(nms[0].claim(), test_failure())], &no_backoff())
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_pull_and_finish() {
+    let (queue, shard) = test_queue(&[]).await;
+    assert_eq!(3, queue.seed(&shard, [(1, 3), (2, 3), (3, 3)]).unwrap());
+    assert_eq!(0, queue.seed(&shard, [(1, 5)]).unwrap());
+
+    let recheck = RecheckPolicy::default();
+    let w1 = queue.pull(&shard, "w1", 2, LEASE, &recheck).await.unwrap();
+    assert_eq!(vec![1, 2], w1.iter().map(|n| n.nm_id).collect::<Vec<_>>());
+    let w2 = queue.pull(&shard, "w2", 10, LEASE, &recheck).await.unwrap();
+    assert_eq!(vec![3], w2.iter().map(|n| n.nm_id).collect::<Vec<_>>());
+    assert_eq!(0, queue.reap_expired(&shard).await.unwrap());
+
+    let lost = queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(
+                w1[0].claim(),
+                2,
+                "a;b".to_string(),
+                vec![1, 2],
+                Variants::new(),
+            )],
+        )
+        .await
+        .unwrap();
+    assert!(lost.is_empty());
+    // захват уже завершен, повторный результат отбрасывается
+    let lost = queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(w1[0].claim(), 0, String::new(), Vec::new(), Variants::new())],
+        )
+        .await
+        .unwrap();
+    assert_eq!(vec![w1[0].claim()], lost);
+    let nm = queue.get(&shard, 1).unwrap();
+    assert!(nm.is_finished && !nm.in_process);
+    assert_eq!(Some(2), nm.new_pics_count);
+    let stats = queue.stats(&shard).await.unwrap();
+    assert_eq!(
+        (3, 1, 1, 2),
+        (
+            stats.total,
+            stats.finished,
+            stats.regressed,
+            stats.in_process
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_release_claims() {
+    let (queue, shard) = test_queue(&[(1, 3), (2, 3), (3, 3)]).await;
+    let recheck = RecheckPolicy::default();
+    let w1 = queue.pull(&shard, "w1", 2, LEASE, &recheck).await.unwrap();
+    let w2 = queue.pull(&shard, "w2", 1, LEASE, &recheck).await.unwrap();
+    assert_eq!(2, queue.release_claims(&shard, "w1").await.unwrap());
+    let nm = queue.get(&shard, 1).unwrap();
+    assert!(!nm.in_process && nm.lease_until.is_none() && nm.worker_id.is_none());
+    assert!(queue.get(&shard, 3).unwrap().in_process);
+
+    // результат, пришедший после остановки, не перетирает освобожденную джобу
+    assert!(!queue.finish_job(&shard, &w1[0].claim()).await.unwrap());
+    assert!(queue.finish_job(&shard, &w2[0].claim()).await.unwrap());
+    let w3 = queue.pull(&shard, "w3", 10, LEASE, &recheck).await.unwrap();
+    assert_eq!(vec![1, 2], w3.iter().map(|n| n.nm_id).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn test_fail_backoff() {
+    let (queue, shard) = test_queue(&[(1, 3)]).await;
+    let recheck = RecheckPolicy::default();
+    let nms = queue.pull(&shard, "w1", 10, LEASE, &recheck).await.unwrap();
+    let later = Backoff {
+        base: Duration::from_secs(3600),
+        ..Backoff::default()
+    };
+    queue
+        .batch_fail_jobs(&shard, vec![(nms[0].claim(), test_failure())], &later)
+        .await
+        .unwrap();
+    assert!(queue
+        .pull(&shard, "w1", 10, LEASE, &recheck)
+        .await
+        .unwrap()
+        .is_empty());
+    let nm = queue.get(&shard, 1).unwrap();
+    assert_eq!((1, Some(503)), (nm.retries, nm.error_status));
+    assert_eq!(1, queue.stats(&shard).await.unwrap().failed_retrying);
+}
+
+#[tokio::test]
+async fn test_dead_letters() {
+    let (queue, shard) = test_queue(&[(1, 3), (3, 3)]).await;
+    let recheck = RecheckPolicy::default();
+    let nms = queue.pull(&shard, "w1", 1, LEASE, &recheck).await.unwrap();
+    let mut claim = nms[0].claim();
+    for _ in 0..RETRIES {
+        assert!(queue
+            .fail_job(&shard, &claim, test_failure(), &no_backoff())
+            .await
+            .unwrap());
+        if let Some(nm) = queue
+            .pull(&shard, "w1", 1, LEASE, &recheck)
+            .await
+            .unwrap()
+            .first()
+        {
+            claim = nm.claim();
+        }
+    }
+    assert!(queue.get(&shard, 1).is_none());
+    let dead = queue.dead_letters(&shard, 10, 0).await.unwrap();
+    assert_eq!(1, dead.len());
+    assert_eq!(RETRIES, dead[0].retries);
+    assert_eq!(Some(503), dead[0].error_status);
+    assert_eq!(RETRIES as usize, dead[0].history.as_array().unwrap().len());
+    let stats = queue.stats(&shard).await.unwrap();
+    assert_eq!((2, 1), (stats.total, stats.exhausted));
+
+    assert_eq!(
+        1,
+        queue.requeue_dead_letters(&shard, vec![1]).await.unwrap()
+    );
+    assert_eq!(0, queue.get(&shard, 1).unwrap().retries);
+    assert_eq!(0, queue.purge_dead_letters(&shard, None).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_requeue_dead_letter_conflict() {
+    let (queue, shard) = test_queue(&[(1, 3)]).await;
+    exhaust(&queue, &shard, &RecheckPolicy::default()).await;
+    assert_eq!(1, queue.dead_letters(&shard, 10, 0).await.unwrap().len());
+
+    // номенклатуру загрузили заново, пока она лежала в dead_letter
+    queue.seed(&shard, [(1, 3)]).unwrap();
+    assert_eq!(
+        0,
+        queue.requeue_dead_letters(&shard, vec![1]).await.unwrap()
+    );
+    let dead = queue.dead_letters(&shard, 10, 0).await.unwrap();
+    assert_eq!(1, dead.len());
+    assert_eq!(RETRIES as usize, dead[0].history.as_array().unwrap().len());
+}
+
+#[tokio::test]
+async fn test_job_filters() {
+    let (queue, shard) = test_queue(&[(1, 3), (2, 3)]).await;
+    let recheck = RecheckPolicy::default();
+    let nms = queue.pull(&shard, "w1", 10, LEASE, &recheck).await.unwrap();
+    queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(
+                nms[0].claim(),
+                2,
+                "a;b".to_string(),
+                vec![1, 2],
+                Variants::new(),
+            )],
+        )
+        .await
+        .unwrap();
+    queue
+        .batch_fail_jobs(
+            &shard,
+            vec![(nms[1].claim(), test_failure())],
+            &no_backoff(),
+        )
+        .await
+        .unwrap();
+
+    let requeued = queue.requeue(&shard, &JobFilter::Regressed).await;
+    assert_eq!(1, requeued.unwrap());
+    let nm = queue.get(&shard, 1).unwrap();
+    assert!(!nm.is_finished && nm.new_pics_count == Some(2));
+    let reset = queue.reset_retries(&shard, &JobFilter::Failed).await;
+    assert_eq!(1, reset.unwrap());
+    assert_eq!(0, queue.get(&shard, 2).unwrap().retries);
+    let cleared = queue
+        .clear_results(&shard, &JobFilter::NmIds(vec![1, 4]))
+        .await;
+    assert_eq!(1, cleared.unwrap());
+    assert_eq!(None, queue.get(&shard, 1).unwrap().new_pics_count);
+    assert_eq!(2, queue.clear(&shard).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_recheck() {
+    // готовая номенклатура возвращается на перепроверку, результат пишется в rechecks
+    let (queue, shard) = test_queue(&[(5, 3)]).await;
+    let nms = queue
+        .pull(&shard, "w1", 10, LEASE, &RecheckPolicy::default())
+        .await
+        .unwrap();
+    queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(
+                nms[0].claim(),
+                3,
+                "a;b;c".to_string(),
+                vec![1, 2, 3],
+                Variants::new(),
+            )],
+        )
+        .await
+        .unwrap();
+    assert!(queue
+        .pull(&shard, "w1", 10, LEASE, &RecheckPolicy::default())
+        .await
+        .unwrap()
+        .is_empty());
+    let recheck = RecheckPolicy {
+        after: Duration::ZERO,
+        regressed_after: Duration::ZERO,
+    };
+    let nms = queue.pull(&shard, "w1", 10, LEASE, &recheck).await.unwrap();
+    assert_eq!(
+        (5, 1, false),
+        (nms[0].nm_id, nms[0].rechecks, nms[0].is_finished)
+    );
+    queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(nms[0].claim(), 1, "a".to_string(), vec![1], Variants::new())],
+        )
+        .await
+        .unwrap();
+    let rechecks = queue.rechecks(&shard, 5).await.unwrap();
+    assert_eq!(1, rechecks.len());
+    assert_eq!(
+        (Some(3), 1),
+        (rechecks[0].previous_pics_count, rechecks[0].new_pics_count)
+    );
+}
+
+#[tokio::test]
+async fn test_failed_recheck_keeps_results() {
+    let (queue, shard) = test_queue(&[(1, 3)]).await;
+    let recheck = RecheckPolicy {
+        after: Duration::ZERO,
+        regressed_after: Duration::ZERO,
+    };
+    let nms = queue.pull(&shard, "w1", 1, LEASE, &recheck).await.unwrap();
+    queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(
+                nms[0].claim(),
+                2,
+                "a;b".to_string(),
+                vec![1, 2],
+                Variants::new(),
+            )],
+        )
+        .await
+        .unwrap();
+
+    // cdn лежит всю перепроверку
+    exhaust(&queue, &shard, &recheck).await;
+    let nm = queue.get(&shard, 1).unwrap();
+    assert!(nm.is_finished && !nm.in_process);
+    assert_eq!((Some(2), vec![1, 2]), (nm.new_pics_count, nm.pics));
+    assert!(queue.dead_letters(&shard, 10, 0).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_reset_results_exhaust_to_dead_letter() {
+    // после requeue и clear_results прежнего результата нет, упавшая джоба хоронится
+    for requeue in [true, false] {
+        let (queue, shard) = test_queue(&[(1, 3)]).await;
+        let recheck = RecheckPolicy::default();
+        let nms = queue.pull(&shard, "w1", 1, LEASE, &recheck).await.unwrap();
+        queue
+            .batch_finish_jobs(
+                &shard,
+                vec![(
+                    nms[0].claim(),
+                    3,
+                    "a;b;c".to_string(),
+                    vec![1, 2, 3],
+                    Variants::new(),
+                )],
+            )
+            .await
+            .unwrap();
+        let filter = JobFilter::NmIds(vec![1]);
+        if requeue {
+            queue.requeue(&shard, &filter)
+        } else {
+            queue.clear_results(&shard, &filter)
+        }
+        .await
+        .unwrap();
+
+        exhaust(&queue, &shard, &recheck).await;
+        assert!(queue.get(&shard, 1).is_none());
+        assert_eq!(1, queue.dead_letters(&shard, 10, 0).await.unwrap().len());
+    }
+}
+
+#[tokio::test]
+async fn test_pics_and_variants() {
+    // третья картинка нашлась после пропуска второй
+    let (queue, shard) = test_queue(&[(5, 3)]).await;
+    let nms = queue
+        .pull(&shard, "w1", 10, LEASE, &RecheckPolicy::default())
+        .await
+        .unwrap();
+    queue
+        .batch_finish_jobs(
+            &shard,
+            vec![(
+                nms[0].claim(),
+                1,
+                "a;c".to_string(),
+                vec![1, 3],
+                Variants::from([
+                    (1, vec!["big.jpg".to_string()]),
+                    (3, vec!["big.jpg".to_string(), "big.webp".to_string()]),
+                ]),
+            )],
+        )
+        .await
+        .unwrap();
+    let nm = queue.get(&shard, 5).unwrap();
+    assert_eq!(vec![2], nm.missing_pics());
+    assert_eq!(2, nm.pic_variants[&3].len());
+    assert_eq!(
+        1,
+        queue.clear_results(&shard, &JobFilter::Gaps).await.unwrap()
+    );
+    let nm = queue.get(&shard, 5).unwrap();
+    assert!(nm.pics.is_empty() && nm.pic_variants.is_empty());
+}
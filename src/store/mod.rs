@@ -1,2 +1,10 @@
+pub mod backoff;
+pub mod export;
+pub mod import;
+pub mod memory_queue;
 pub mod models;
+pub mod recheck;
+pub mod shard;
+pub mod signals;
+pub mod spill;
 pub mod sqlx_queue;
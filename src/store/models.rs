@@ -1,4 +1,16 @@
-#[derive(Debug, sqlx::FromRow)]
+use {
+    serde::{Deserialize, Serialize},
+    sqlx::types::{
+        chrono::{DateTime, Utc},
+        Json,
+    },
+    std::{collections::BTreeMap, fmt},
+};
+
+/// найденные варианты по номеру картинки, вариант - "размер.формат": {1: ["big.jpg", "tm.webp"]}
+pub type Variants = BTreeMap<i16, Vec<String>>;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct Nomenclature {
     pub nm_id: i64,
     pub old_pics_count: i16,
@@ -7,4 +19,225 @@ pub struct Nomenclature {
     pub in_process: bool,
     pub is_finished: bool,
     pub retries: i64,
+    pub worker_id: Option<String>,
+    pub claimed_at: Option<DateTime<Utc>>,
+    pub lease_until: Option<DateTime<Utc>>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub error: String,
+    pub error_kind: Option<String>,
+    pub error_status: Option<i16>,
+    pub error_url: Option<String>,
+    pub claim_generation: i64,
+    /// конец последней успешной проверки
+    pub finished_at: Option<DateTime<Utc>>,
+    /// сколько раз готовую номенклатуру брали на перепроверку
+    pub rechecks: i32,
+    /// номера найденных картинок по возрастанию, в том числе после пропусков
+    pub pics: Vec<i16>,
+    /// какие размеры и форматы нашлись для каждой картинки при последней проверке
+    pub pic_variants: Json<Variants>,
+}
+
+impl Nomenclature {
+    /// номера картинок до old_pics_count, которых не нашлось при последней проверке
+    pub fn missing_pics(&self) -> Vec<i16> {
+        (1..=self.old_pics_count)
+            .filter(|idx| !self.pics.contains(idx))
+            .collect()
+    }
+
+    /// токен текущего захвата, с ним воркер сдает результат
+    pub fn claim(&self) -> Claim {
+        Claim {
+            nm_id: self.nm_id,
+            worker_id: self.worker_id.clone().unwrap_or_default(),
+            generation: self.claim_generation,
+        }
+    }
+}
+
+/// захват джобы воркером; результат применяется, только если джоба
+/// с тех пор не была отдана другому воркеру или сброшена
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Claim {
+    pub nm_id: i64,
+    pub worker_id: String,
+    pub generation: i64,
+}
+
+/// результат перепроверки готовой номенклатуры; previous_pics_count - итог предыдущей проверки
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct Recheck {
+    pub shard: String,
+    pub nm_id: i64,
+    pub checked_at: DateTime<Utc>,
+    pub previous_pics_count: Option<i16>,
+    pub new_pics_count: i16,
+    pub good_links: String,
+}
+
+/// запущенный process; finished, failed и lost_claims - счетчики с момента старта
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct Worker {
+    pub worker_id: String,
+    pub hostname: String,
+    pub shards: Vec<String>,
+    pub version: String,
+    pub started_at: DateTime<Utc>,
+    pub heartbeat_at: DateTime<Utc>,
+    pub finished: i64,
+    pub failed: i64,
+    pub lost_claims: i64,
+}
+
+/// номенклатура, исчерпавшая ретраи; history - массив всех ошибок по попыткам
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DeadLetter {
+    pub shard: String,
+    pub nm_id: i64,
+    pub old_pics_count: i16,
+    pub retries: i64,
+    pub error: String,
+    pub error_kind: Option<String>,
+    pub error_status: Option<i16>,
+    pub error_url: Option<String>,
+    pub history: serde_json::Value,
+    pub worker_id: Option<String>,
+    pub dead_at: DateTime<Utc>,
+}
+
+/// какие джобы шарда затрагивают requeue, reset_retries и clear_results
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobFilter {
+    /// взяты в работу, независимо от аренды
+    Stuck,
+    /// проверены и не нашлось ни одной картинки
+    ZeroPics,
+    /// упали хотя бы раз и еще не проверены
+    Failed,
+    NmIds(Vec<i64>),
+    /// проверены, но картинок стало меньше, чем было
+    Regressed,
+    /// проверены, и после пропуска нашлись еще картинки
+    Gaps,
+}
+
+/// прогресс шарда; exhausted лежат в dead_letter, но входят в total
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct ShardStats {
+    pub total: i64,
+    /// ждут первой попытки
+    pub pending: i64,
+    pub in_process: i64,
+    pub finished: i64,
+    /// упали хотя бы раз и ждут ретрая
+    pub failed_retrying: i64,
+    pub exhausted: i64,
+    /// проверены, но картинок стало меньше, чем было
+    pub regressed: i64,
+}
+
+impl fmt::Display for ShardStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "total={} pending={} in_process={} finished={} failed_retrying={} exhausted={} regressed={}",
+            self.total,
+            self.pending,
+            self.in_process,
+            self.finished,
+            self.failed_retrying,
+            self.exhausted,
+            self.regressed
+        )
+    }
+}
+
+/// причина, по которой номенклатура не была проверена
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureKind {
+    /// запрос не уложился в таймаут
+    Timeout,
+    /// не удалось установить или удержать соединение
+    Connect,
+    /// cdn ответил статусом, отличным от 200 и 404
+    Status,
+    /// прочие ошибки построения или выполнения запроса
+    Request,
+    /// ошибки самого воркера, например упавшая задача
+    Internal,
+}
+
+impl FailureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Timeout => "timeout",
+            FailureKind::Connect => "connect",
+            FailureKind::Status => "status",
+            FailureKind::Request => "request",
+            FailureKind::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// запись о неудачной попытке, сохраняется в шарде вместе с ретраем
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub status: Option<i16>,
+    pub url: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "kind={}", self.kind)?;
+        if let Some(status) = self.status {
+            write!(f, " status={status}")?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, " url={url}")?;
+        }
+        write!(f, " err={}", self.message)
+    }
+}
+
+/// одна проверка номенклатуры воркером, строка append-only таблицы attempts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Attempt {
+    pub shard: String,
+    pub nm_id: i64,
+    pub worker_id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// статус ответа по каждой ссылке: картинки по порядку, для каждой - все размеры и форматы,
+    /// за ними пробы поиска картинок после old_pics_count и ссылки на найденные;
+    /// 0 - ответа не было или проверка прервана
+    pub statuses: Vec<i16>,
+    /// finished или failed
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+/// результаты одной пачки, записываются в шард одной транзакцией
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    /// захват, new_pics_count, good_links, pics, pic_variants
+    pub finished: Vec<(Claim, i16, String, Vec<i16>, Variants)>,
+    pub failed: Vec<(Claim, Failure)>,
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+}
+
+impl Batch {
+    pub fn is_empty(&self) -> bool {
+        self.finished.is_empty() && self.failed.is_empty() && self.attempts.is_empty()
+    }
 }
@@ -0,0 +1,93 @@
+use {
+    crate::store::models::Nomenclature,
+    chrono::{DateTime, Utc},
+    std::time::Duration,
+};
+
+/// когда готовые номенклатуры снова отдаются в pull; перепроверки выдаются,
+/// только когда свежих джоб в шарде не осталось
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecheckPolicy {
+    /// через сколько после последней проверки перепроверять
+    pub after: Duration,
+    /// то же для номенклатур, у которых картинок стало меньше, чем было
+    pub regressed_after: Duration,
+}
+
+impl Default for RecheckPolicy {
+    fn default() -> Self {
+        Self {
+            after: Duration::from_secs(30 * 24 * 3600),
+            regressed_after: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
+impl RecheckPolicy {
+    /// интервал для номенклатуры по результату ее последней проверки
+    pub fn interval(&self, nm: &Nomenclature) -> Duration {
+        match nm.new_pics_count {
+            Some(new) if new < nm.old_pics_count => self.regressed_after,
+            _ => self.after,
+        }
+    }
+
+    /// готовая номенклатура, которую пора перепроверить; без finished_at - сразу,
+    /// после упавшей перепроверки - не раньше next_attempt_at
+    pub fn due(&self, nm: &Nomenclature, now: DateTime<Utc>) -> bool {
+        if !nm.is_finished || nm.in_process || nm.next_attempt_at.is_some_and(|at| at > now) {
+            return false;
+        }
+        match nm.finished_at {
+            None => true,
+            Some(at) => now
+                .signed_duration_since(at)
+                .to_std()
+                .is_ok_and(|elapsed| elapsed >= self.interval(nm)),
+        }
+    }
+}
+
+#[test]
+fn test_recheck_due() {
+    let policy = RecheckPolicy {
+        after: Duration::from_secs(3600),
+        regressed_after: Duration::from_secs(60),
+    };
+    let now = Utc::now();
+    let mut nm = Nomenclature {
+        nm_id: 1,
+        old_pics_count: 3,
+        new_pics_count: Some(3),
+        good_links: String::new(),
+        in_process: false,
+        is_finished: true,
+        retries: 0,
+        worker_id: None,
+        claimed_at: None,
+        lease_until: None,
+        next_attempt_at: None,
+        error: String::new(),
+        error_kind: None,
+        error_status: None,
+        error_url: None,
+        claim_generation: 0,
+        finished_at: None,
+        rechecks: 0,
+        pics: Vec::new(),
+        pic_variants: sqlx::types::Json(Default::default()),
+    };
+    assert!(policy.due(&nm, now));
+
+    nm.finished_at = Some(now - chrono::Duration::minutes(10));
+    assert!(!policy.due(&nm, now));
+    nm.new_pics_count = Some(2);
+    assert!(policy.due(&nm, now));
+
+    nm.finished_at = Some(now + chrono::Duration::minutes(10));
+    assert!(!policy.due(&nm, now));
+
+    nm.finished_at = None;
+    nm.is_finished = false;
+    assert!(!policy.due(&nm, now));
+}
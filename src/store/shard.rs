@@ -0,0 +1,56 @@
+use std::{fmt, str::FromStr};
+
+/// имя шарда, оно же имя секции nomenclatures; собирается только из валидного имени,
+/// а в sql попадает только через ident(). Queue::shard дополнительно проверяет, что шард
+/// есть в реестре и у него есть таблица
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Shard(String);
+
+impl Shard {
+    /// `[a-z_][a-z0-9_]*` не длиннее 63 символов - имя, которое postgres не усечет и не потребует кавычек
+    pub fn new(name: &str) -> anyhow::Result<Self> {
+        let mut chars = name.chars();
+        let valid = matches!(chars.next(), Some('a'..='z' | '_'))
+            && chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_'))
+            && name.len() <= 63;
+        if !valid {
+            anyhow::bail!("invalid shard name [{name}]");
+        }
+        Ok(Self(name.to_string()))
+    }
+
+    /// имя в кавычках для подстановки в sql как идентификатор
+    pub fn ident(&self) -> String {
+        format!("\"{}\"", self.0.replace('"', "\"\""))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+#[test]
+fn test_shard_new() {
+    assert!(Shard::new("shard_1").is_ok());
+    assert!(Shard::new("_shard").is_ok());
+    assert!(Shard::new("").is_err());
+    assert!(Shard::new("1shard").is_err());
+    assert!(Shard::new("Shard_1").is_err());
+    assert!(Shard::new("shard_1; drop table shards").is_err());
+    assert!(Shard::new(&"s".repeat(64)).is_err());
+    assert_eq!("\"shard_1\"", Shard::new("shard_1").unwrap().ident());
+}
@@ -0,0 +1,36 @@
+use {
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::sync::Notify,
+};
+
+/// Notify на каждый шард: уведомление о новых джобах будит только ждущих этот шард,
+/// пришедшее без ждущих не теряется и сработает на следующем wait
+#[derive(Debug, Clone, Default)]
+pub struct JobSignals(Arc<Mutex<HashMap<String, Arc<Notify>>>>);
+
+impl JobSignals {
+    pub fn notify(&self, shard: &str) {
+        self.get(shard).notify_one();
+    }
+
+    /// false - уведомления не было за timeout
+    pub async fn wait(&self, shard: &str, timeout: Duration) -> bool {
+        let notify = self.get(shard);
+        tokio::time::timeout(timeout, notify.notified())
+            .await
+            .is_ok()
+    }
+
+    fn get(&self, shard: &str) -> Arc<Notify> {
+        self.0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(shard.to_string())
+            .or_default()
+            .clone()
+    }
+}
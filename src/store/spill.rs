@@ -0,0 +1,285 @@
+use {
+    crate::store::{models::Batch, shard::Shard},
+    serde::{Deserialize, Serialize},
+    std::{
+        fs::{self, File, OpenOptions},
+        io::{BufRead, BufReader, ErrorKind, Write},
+        path::PathBuf,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+    tracing::warn,
+};
+
+/// ndjson файл с пачками, которые не удалось записать в postgres;
+/// один файл - один процесс: задачи процесса пишут в него через общую блокировку,
+/// пачки из головы убирает только один воспроизводящий их;
+/// пачки, которые очередь так и не приняла, и нечитаемые строки откладываются в соседний .failed файл
+#[derive(Debug, Clone)]
+pub struct Spill {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    shard: String,
+    batch: Batch,
+    /// сколько раз очередь отвергла пачку
+    #[serde(default)]
+    attempts: u32,
+}
+
+impl Spill {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Arc::default(),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// куда уходят пачки, исчерпавшие попытки: shard_1.spill.failed.ndjson
+    pub fn failed_path(&self) -> PathBuf {
+        self.path.with_extension("failed.ndjson")
+    }
+
+    /// дописывает пачку в конец файла и дожидается записи на диск
+    pub fn append(&self, shard: &Shard, batch: &Batch) -> anyhow::Result<()> {
+        let _guard = self.lock();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_vec(&Entry {
+            shard: shard.to_string(),
+            batch: batch.clone(),
+            attempts: 0,
+        })?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// все отложенные пачки по порядку; отсутствующий файл - пустой список
+    pub fn load(&self) -> anyhow::Result<Vec<(Shard, Batch)>> {
+        let _guard = self.lock();
+        self.read()?
+            .into_iter()
+            .map(|entry| Ok((Shard::new(&entry.shard)?, entry.batch)))
+            .collect()
+    }
+
+    /// убирает первую пачку, когда она записана в очередь; опустевший файл удаляется
+    pub fn pop_front(&self) -> anyhow::Result<()> {
+        let _guard = self.lock();
+        let entries = self.read()?;
+        self.write(entries.get(1..).unwrap_or_default())
+    }
+
+    /// очередь отвергла первую пачку: она уходит в конец файла, чтобы не держать остальные,
+    /// а на max_attempts-й раз - в failed_path; возвращает число попыток
+    pub fn defer_front(&self, max_attempts: u32) -> anyhow::Result<u32> {
+        let _guard = self.lock();
+        let mut entries = self.read()?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        let mut entry = entries.remove(0);
+        entry.attempts += 1;
+        let attempts = entry.attempts;
+        if attempts >= max_attempts {
+            self.append_failed(&[serde_json::to_vec(&entry)?])?;
+        } else {
+            entries.push(entry);
+        }
+        self.write(&entries)?;
+        Ok(attempts)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// пачки из файла; строки, которые не разобрать, например оборванную при падении
+    /// процесса запись, переносит в failed_path, чтобы они не держали остальные
+    fn read(&self) -> anyhow::Result<Vec<Entry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = Vec::new();
+        let mut corrupt = Vec::new();
+        for line in BufReader::new(file).split(b'\n') {
+            let line = line?;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => {
+                    warn!(
+                        "unreadable spill entry was moved to {}: {err}",
+                        self.failed_path().display()
+                    );
+                    corrupt.push(line);
+                }
+            }
+        }
+        if !corrupt.is_empty() {
+            self.append_failed(&corrupt)?;
+            self.write(&entries)?;
+        }
+        Ok(entries)
+    }
+
+    /// дописывает строки в failed_path и дожидается записи на диск
+    fn append_failed(&self, lines: &[Vec<u8>]) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.failed_path())?;
+        for line in lines {
+            file.write_all(line)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// заменяет содержимое файла пачками, пустой список удаляет файл
+    fn write(&self, entries: &[Entry]) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in entries {
+            serde_json::to_writer(&mut file, entry)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_data()?;
+        fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_spill() {
+    use crate::store::models::{Claim, Failure, FailureKind, Variants};
+
+    let spill =
+        Spill::new(std::env::temp_dir().join(format!("spill-{}.ndjson", std::process::id())));
+    spill.write(&[]).unwrap();
+    assert!(spill.load().unwrap().is_empty());
+
+    let claim = |nm_id| Claim {
+        nm_id,
+        worker_id: "w1".to_string(),
+        generation: 1,
+    };
+    let batch = Batch {
+        finished: vec![(claim(1), 2, "a;b".to_string(), vec![1, 2], Variants::new())],
+        failed: vec![(
+            claim(2),
+            Failure {
+                kind: FailureKind::Timeout,
+                status: None,
+                url: Some("https://example.com".to_string()),
+                message: "request timed out".to_string(),
+            },
+        )],
+        attempts: Vec::new(),
+    };
+    let (shard_1, shard_2) = (
+        Shard::new("shard_1").unwrap(),
+        Shard::new("shard_2").unwrap(),
+    );
+    spill.append(&shard_1, &batch).unwrap();
+    spill.append(&shard_2, &Batch::default()).unwrap();
+    let entries = spill.load().unwrap();
+    assert_eq!(2, entries.len());
+    assert_eq!((shard_1, batch), entries[0]);
+
+    spill.pop_front().unwrap();
+    assert_eq!(
+        vec![shard_2],
+        spill
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect::<Vec<_>>()
+    );
+    spill.pop_front().unwrap();
+    assert!(!spill.path().exists());
+}
+
+#[test]
+fn test_spill_defer() {
+    let spill =
+        Spill::new(std::env::temp_dir().join(format!("spill-defer-{}.ndjson", std::process::id())));
+    spill.write(&[]).unwrap();
+    let _ = fs::remove_file(spill.failed_path());
+    let (shard_1, shard_2) = (
+        Shard::new("shard_1").unwrap(),
+        Shard::new("shard_2").unwrap(),
+    );
+    spill.append(&shard_1, &Batch::default()).unwrap();
+    spill.append(&shard_2, &Batch::default()).unwrap();
+
+    // отвергнутая пачка пропускает следующую вперед
+    assert_eq!(1, spill.defer_front(2).unwrap());
+    let shards = |spill: &Spill| {
+        spill
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(vec![shard_2.clone(), shard_1.clone()], shards(&spill));
+    spill.pop_front().unwrap();
+
+    assert_eq!(2, spill.defer_front(2).unwrap());
+    assert!(!spill.path().exists());
+    let failed = Spill::new(spill.failed_path());
+    assert_eq!(vec![shard_1], shards(&failed));
+    failed.write(&[]).unwrap();
+}
+
+#[test]
+fn test_spill_truncated() {
+    let spill = Spill::new(
+        std::env::temp_dir().join(format!("spill-truncated-{}.ndjson", std::process::id())),
+    );
+    spill.write(&[]).unwrap();
+    let _ = fs::remove_file(spill.failed_path());
+    let shard_1 = Shard::new("shard_1").unwrap();
+    spill.append(&shard_1, &Batch::default()).unwrap();
+    // процесс упал посреди записи второй пачки
+    let mut file = OpenOptions::new().append(true).open(spill.path()).unwrap();
+    file.write_all(br#"{"shard":"shard_2","batch":{"fin"#)
+        .unwrap();
+
+    assert_eq!(
+        vec![(shard_1.clone(), Batch::default())],
+        spill.load().unwrap()
+    );
+    assert_eq!(
+        r#"{"shard":"shard_2","batch":{"fin"#,
+        fs::read_to_string(spill.failed_path()).unwrap().trim_end()
+    );
+    // оборванная строка убрана из файла, следующие пачки дописываются как обычно
+    spill.append(&shard_1, &Batch::default()).unwrap();
+    assert_eq!(2, spill.load().unwrap().len());
+    spill.write(&[]).unwrap();
+    fs::remove_file(spill.failed_path()).unwrap();
+}
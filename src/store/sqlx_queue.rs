@@ -1,48 +1,164 @@
 use {
-    crate::store::models::Nomenclature,
+    crate::store::{
+        backoff::Backoff,
+        models::{
+            Attempt, Batch, Claim, DeadLetter, Failure, JobFilter, Nomenclature, Recheck,
+            ShardStats, Variants, Worker,
+        },
+        recheck::RecheckPolicy,
+        shard::Shard,
+        signals::JobSignals,
+    },
     async_trait::async_trait,
-    serde::{Deserialize, Serialize},
     sqlx::{
-        postgres::PgPoolOptions,
-        {Pool, Postgres},
+        postgres::{PgListener, PgPoolOptions},
+        {Pool, Postgres, Transaction},
     },
-    tracing::{debug, info, trace},
+    std::{fmt, sync::Arc, time::Duration},
+    tokio::{sync::Mutex, task::JoinHandle},
+    tracing::{debug, info, warn},
 };
 
 pub const RETRIES: i64 = 3;
 
+/// сколько раз commit_batch повторяет транзакцию при временных ошибках postgres
+pub const COMMIT_ATTEMPTS: u32 = 3;
+
+/// канал, в который шарды пишут свое имя при появлении новых джоб
+pub const JOBS_CHANNEL: &str = "shard_jobs";
+
 #[async_trait]
 pub trait Queue: Send + Sync + std::fmt::Debug {
-    /// забирает из таблицы нужное количество джоб
-    async fn pull(&self, shard: &str, jobs: i64) -> anyhow::Result<Vec<Nomenclature>>;
-    /// будет ставить метку о завершении работы
-    async fn finish_job(&self, shard: &str, nm_id: i64) -> anyhow::Result<()>;
-    /// вписывает недоступные ссылки картинок и инкрементирует счетчик ретраев
-    async fn fail_job(&self, shard: &str, nm_id: i64) -> anyhow::Result<()>;
-    /// полностью очищает таблицу
-    async fn clear(&self) -> anyhow::Result<()>;
-    /// батч для завершенных
+    /// забирает из таблицы нужное количество джоб и выдает воркеру аренду на них;
+    /// захват, а также его завершение через finish/fail, увеличивает claim_generation.
+    /// когда свежих джоб нет, отдает готовые, которым пора на перепроверку по recheck:
+    /// они снова становятся незавершенными с обнуленными ретраями
+    async fn pull(
+        &self,
+        shard: &Shard,
+        worker_id: &str,
+        jobs: i64,
+        lease: Duration,
+        recheck: &RecheckPolicy,
+    ) -> anyhow::Result<Vec<Nomenclature>>;
+    /// продлевает аренду джоб, захваты которых все еще у воркера, пока их пачка проверяется;
+    /// возвращает количество продленных
+    async fn extend_lease(
+        &self,
+        shard: &Shard,
+        claims: &[Claim],
+        lease: Duration,
+    ) -> anyhow::Result<u64>;
+    /// возвращает в очередь джобы, аренда которых истекла
+    async fn reap_expired(&self, shard: &Shard) -> anyhow::Result<u64>;
+    /// возвращает в очередь джобы воркеров, чей heartbeat старше stale_after,
+    /// не дожидаясь истечения аренды
+    async fn reap_dead_workers(&self, shard: &Shard, stale_after: Duration) -> anyhow::Result<u64>;
+    /// при штатной остановке возвращает в очередь все джобы воркера и снимает с них захват,
+    /// чтобы не ждать истечения аренды; поздние finish/fail этого воркера будут потеряны
+    async fn release_claims(&self, shard: &Shard, worker_id: &str) -> anyhow::Result<u64>;
+    /// ставит метку о завершении работы; false - захват потерян и джоба не тронута
+    async fn finish_job(&self, shard: &Shard, claim: &Claim) -> anyhow::Result<bool>;
+    /// сохраняет причину ошибки, инкрементирует счетчик ретраев
+    /// и откладывает следующую попытку согласно backoff;
+    /// исчерпавшие RETRIES джобы переносятся в dead_letter, а проверенные раньше
+    /// снова считаются готовыми с прежним результатом;
+    /// false - захват потерян и джоба не тронута
+    async fn fail_job(
+        &self,
+        shard: &Shard,
+        claim: &Claim,
+        failure: Failure,
+        backoff: &Backoff,
+    ) -> anyhow::Result<bool>;
+    /// удаляет все джобы шарда, dead_letter не трогает; возвращает количество удаленных
+    async fn clear(&self, shard: &Shard) -> anyhow::Result<u64>;
+    /// возвращает подходящие джобы в очередь, ретраи и результаты сохраняются;
+    /// finished_at стирается, поэтому исчерпавшая ретраи джоба уйдет в dead_letter
+    async fn requeue(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64>;
+    /// обнуляет ретраи и последнюю ошибку, история failures сохраняется
+    async fn reset_retries(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64>;
+    /// стирает new_pics_count, good_links, pics и finished_at: готовые джобы
+    /// сразу уходят на перепроверку, а не возвращаются к стертому результату
+    async fn clear_results(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64>;
+    /// батч для завершенных; возвращает потерянные захваты
     async fn batch_finish_jobs(
         &self,
-        shard: &str,
-        nms: Vec<(i64, i16, String)>,
-    ) -> anyhow::Result<()>;
-    /// батч для ошибок
-    async fn batch_fail_jobs(&self, shard: &str, nms: Vec<i64>) -> anyhow::Result<()>;
+        shard: &Shard,
+        nms: Vec<(Claim, i16, String, Vec<i16>, Variants)>,
+    ) -> anyhow::Result<Vec<Claim>>;
+    /// батч для ошибок, исчерпавшие RETRIES джобы переносятся в dead_letter
+    /// или возвращаются к прежнему результату, как в fail_job; возвращает потерянные захваты
+    async fn batch_fail_jobs(
+        &self,
+        shard: &Shard,
+        nms: Vec<(Claim, Failure)>,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>>;
+    /// история попыток номенклатуры в порядке начала
+    async fn attempts(&self, shard: &Shard, nm_id: i64) -> anyhow::Result<Vec<Attempt>>;
+    /// результаты перепроверок номенклатуры по порядку
+    async fn rechecks(&self, shard: &Shard, nm_id: i64) -> anyhow::Result<Vec<Recheck>>;
+    /// записывает завершенные и упавшие джобы пачки вместе с попытками одной транзакцией;
+    /// возвращает потерянные захваты, их результаты отброшены. пачку можно записать повторно:
+    /// попытки не задвоятся, а уже записанные результаты не считаются потерянными
+    async fn commit_batch(
+        &self,
+        shard: &Shard,
+        batch: &Batch,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>>;
+    /// список джоб шарда, которые исчерпали ретраи
+    async fn dead_letters(
+        &self,
+        shard: &Shard,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<DeadLetter>>;
+    /// возвращает выбранные джобы из dead_letter обратно в шард с обнуленными ретраями
+    async fn requeue_dead_letters(&self, shard: &Shard, nm_ids: Vec<i64>) -> anyhow::Result<u64>;
+    /// удаляет выбранные джобы из dead_letter, None - все джобы шарда
+    async fn purge_dead_letters(
+        &self,
+        shard: &Shard,
+        nm_ids: Option<Vec<i64>>,
+    ) -> anyhow::Result<u64>;
+    /// шарды из реестра, у которых есть таблица, в естественном порядке: shard_2 раньше shard_10
+    async fn shards(&self) -> anyhow::Result<Vec<Shard>>;
+    /// шард по имени, только если он есть в реестре и его таблица существует
+    async fn shard(&self, name: &str) -> anyhow::Result<Shard>;
+    /// создает таблицу шарда с колонками, которые ожидает Nomenclature, и регистрирует ее
+    async fn create_shard(&self, shard: &Shard) -> anyhow::Result<()>;
+    /// ждет уведомления о новых джобах в шарде, но не дольше timeout;
+    /// false - уведомления не было и стоит просто повторить pull
+    async fn wait_for_jobs(&self, shard: &Shard, timeout: Duration) -> anyhow::Result<bool>;
+    /// счетчики прогресса шарда
+    async fn stats(&self, shard: &Shard) -> anyhow::Result<ShardStats>;
+    /// шард не из except с наибольшим числом джоб, которые можно забрать прямо сейчас
+    async fn busiest_shard(&self, except: &[Shard]) -> anyhow::Result<Option<Shard>>;
+    /// регистрирует воркера или обновляет его heartbeat и счетчики; heartbeat_at ставит очередь
+    async fn heartbeat(&self, worker: &Worker) -> anyhow::Result<()>;
+    /// удаляет воркера из реестра при штатной остановке
+    async fn deregister_worker(&self, worker_id: &str) -> anyhow::Result<()>;
+    /// все зарегистрированные воркеры, включая упавшие
+    async fn workers(&self) -> anyhow::Result<Vec<Worker>>;
 }
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct SqlxPool {
     pub client: Pool<Postgres>,
+    /// уведомления канала shard_jobs, разложенные по шардам
+    jobs: JobSignals,
+    /// задача с отдельным соединением под LISTEN, запускается при первом wait_for_jobs
+    listener: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Database {
-    host: String,
-    port: String,
-    user: String,
-    password: String,
-    database: String,
+impl fmt::Debug for SqlxPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqlxPool")
+            .field("client", &self.client)
+            .finish_non_exhaustive()
+    }
 }
 
 impl SqlxPool {
@@ -54,7 +170,72 @@ impl SqlxPool {
         database: String,
     ) -> anyhow::Result<Self> {
         let client = Self::connect(host, port, user, password, database).await?;
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            jobs: JobSignals::default(),
+            listener: Arc::default(),
+        })
+    }
+
+    /// применяет встроенные в бинарь миграции из migrations/
+    pub async fn migrate(&self) -> anyhow::Result<()> {
+        sqlx::migrate!().run(&self.client).await?;
+        Ok(())
+    }
+
+    /// один слушатель канала на пул, сколько бы шардов ни ждали джоб; упавший
+    /// перезапускается следующим wait_for_jobs
+    async fn listen(&self) -> anyhow::Result<()> {
+        let mut guard = self.listener.lock().await;
+        if guard.as_ref().is_some_and(|task| !task.is_finished()) {
+            return Ok(());
+        }
+        let mut listener = PgListener::connect_with(&self.client).await?;
+        listener.listen(JOBS_CHANNEL).await?;
+        let jobs = self.jobs.clone();
+        *guard = Some(tokio::spawn(async move {
+            loop {
+                match listener.recv().await {
+                    Ok(notification) => jobs.notify(notification.payload()),
+                    Err(err) => {
+                        warn!("{JOBS_CHANNEL} listener failed: {err}");
+                        return;
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// обновляет подходящие под filter джобы в одной транзакции и будит воркеров шарда
+    async fn update_where(
+        &self,
+        shard: &Shard,
+        set: &str,
+        filter: &JobFilter,
+    ) -> anyhow::Result<u64> {
+        let nm_ids = match filter {
+            JobFilter::NmIds(nm_ids) => nm_ids.clone(),
+            _ => Vec::new(),
+        };
+        let mut tx = self.client.begin().await?;
+        let result = sqlx::query(&format!(
+            "update {table} set {set} where {}",
+            job_filter_sql(filter),
+            table = shard.ident()
+        ))
+        .bind(nm_ids)
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() > 0 {
+            sqlx::query("select pg_notify($1, $2)")
+                .bind(JOBS_CHANNEL)
+                .bind(shard.as_str())
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(result.rows_affected())
     }
 
     async fn connect(
@@ -74,120 +255,861 @@ impl SqlxPool {
 
 #[async_trait]
 impl Queue for SqlxPool {
-    async fn pull(&self, shard: &str, jobs: i64) -> anyhow::Result<Vec<Nomenclature>> {
+    async fn pull(
+        &self,
+        shard: &Shard,
+        worker_id: &str,
+        jobs: i64,
+        lease: Duration,
+        recheck: &RecheckPolicy,
+    ) -> anyhow::Result<Vec<Nomenclature>> {
         let result: Vec<Nomenclature> = sqlx::query_as(&format!(
             "update {}
-        set in_process = true
+        set in_process = true,
+            worker_id = $3,
+            claim_generation = claim_generation + 1,
+            claimed_at = now(),
+            lease_until = now() + make_interval(secs => $4)
         where nm_id in (
             select nm_id from {}
             where in_process = false and is_finished = false and retries < $1
+                and (next_attempt_at is null or next_attempt_at <= now())
             for update skip locked
             limit $2
         )
         returning *",
-            shard, shard
+            shard.ident(),
+            shard.ident()
+        ))
+        .bind(RETRIES)
+        .bind(jobs)
+        .bind(worker_id)
+        .bind(lease.as_secs_f64())
+        .fetch_all(&self.client)
+        .await?;
+        if !result.is_empty() {
+            return Ok(result);
+        }
+
+        let result: Vec<Nomenclature> = sqlx::query_as(&format!(
+            "update {table}
+        set in_process = true,
+            is_finished = false,
+            retries = 0,
+            next_attempt_at = null,
+            rechecks = rechecks + 1,
+            worker_id = $2,
+            claim_generation = claim_generation + 1,
+            claimed_at = now(),
+            lease_until = now() + make_interval(secs => $3)
+        where nm_id in (
+            select nm_id from {table}
+            where in_process = false and is_finished = true
+                and (next_attempt_at is null or next_attempt_at <= now())
+                and (finished_at is null or finished_at <= now() - make_interval(secs =>
+                    case when new_pics_count < old_pics_count then $5 else $4 end))
+            for update skip locked
+            limit $1
+        )
+        returning *",
+            table = shard.ident()
         ))
-        .bind(&RETRIES)
-        .bind(&jobs)
+        .bind(jobs)
+        .bind(worker_id)
+        .bind(lease.as_secs_f64())
+        .bind(recheck.after.as_secs_f64())
+        .bind(recheck.regressed_after.as_secs_f64())
         .fetch_all(&self.client)
         .await?;
         Ok(result)
     }
 
-    async fn finish_job(&self, shard: &str, nm_id: i64) -> anyhow::Result<()> {
-        sqlx::query(&format!(
+    async fn extend_lease(
+        &self,
+        shard: &Shard,
+        claims: &[Claim],
+        lease: Duration,
+    ) -> anyhow::Result<u64> {
+        if claims.is_empty() {
+            return Ok(0);
+        }
+        let result = sqlx::query(&format!(
+            "update {} as n
+        set lease_until = now() + make_interval(secs => $4)
+        from unnest($1::int8[], $2::text[], $3::int8[]) as c(nm_id, worker_id, claim_generation)
+        where c.nm_id = n.nm_id and n.in_process = true
+            and n.worker_id = c.worker_id and n.claim_generation = c.claim_generation",
+            shard.ident()
+        ))
+        .bind(claims.iter().map(|claim| claim.nm_id).collect::<Vec<_>>())
+        .bind(
+            claims
+                .iter()
+                .map(|claim| claim.worker_id.as_str())
+                .collect::<Vec<_>>(),
+        )
+        .bind(
+            claims
+                .iter()
+                .map(|claim| claim.generation)
+                .collect::<Vec<_>>(),
+        )
+        .bind(lease.as_secs_f64())
+        .execute(&self.client)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn reap_expired(&self, shard: &Shard) -> anyhow::Result<u64> {
+        // lease_until is null - строки, взятые в работу до появления аренды
+        let result = sqlx::query(&format!(
             "update {}
-        set in_process = false, is_finished = true
-        where nm_id = $1",
-            shard
+        set in_process = false, lease_until = null
+        where in_process = true and (lease_until is null or lease_until < now())",
+            shard.ident()
         ))
-        .bind(&nm_id)
         .execute(&self.client)
         .await?;
-        Ok(())
+        if result.rows_affected() > 0 {
+            notify_jobs(&self.client, shard).await?;
+        }
+        Ok(result.rows_affected())
     }
 
-    async fn fail_job(&self, shard: &str, nm_id: i64) -> anyhow::Result<()> {
-        sqlx::query(&format!(
-            "UPDATE {}
-            SET in_process = false, retries = retries + 1
-            WHERE nm_id = $1",
-            shard
+    async fn release_claims(&self, shard: &Shard, worker_id: &str) -> anyhow::Result<u64> {
+        let result = sqlx::query(&format!(
+            "update {}
+        set in_process = false, lease_until = null, worker_id = null,
+            claim_generation = claim_generation + 1
+        where in_process = true and worker_id = $1",
+            shard.ident()
         ))
-        .bind(&nm_id)
+        .bind(worker_id)
         .execute(&self.client)
         .await?;
-        Ok(())
+        if result.rows_affected() > 0 {
+            notify_jobs(&self.client, shard).await?;
+        }
+        Ok(result.rows_affected())
+    }
+
+    async fn reap_dead_workers(&self, shard: &Shard, stale_after: Duration) -> anyhow::Result<u64> {
+        let result = sqlx::query(&format!(
+            "update {table}
+        set in_process = false, lease_until = null
+        where in_process = true and worker_id in (
+            select worker_id from workers
+            where heartbeat_at < now() - make_interval(secs => $1)
+        )",
+            table = shard.ident()
+        ))
+        .bind(stale_after.as_secs_f64())
+        .execute(&self.client)
+        .await?;
+        if result.rows_affected() > 0 {
+            notify_jobs(&self.client, shard).await?;
+        }
+        Ok(result.rows_affected())
     }
 
-    async fn clear(&self) -> anyhow::Result<()> {
-        sqlx::query("DELETE FROM public.nomenclatures")
+    async fn finish_job(&self, shard: &Shard, claim: &Claim) -> anyhow::Result<bool> {
+        let result = sqlx::query(&format!(
+            "update {}
+        set in_process = false, is_finished = true, lease_until = null, next_attempt_at = null,
+            error = '', error_kind = null, error_status = null, error_url = null,
+            claim_generation = claim_generation + 1, finished_at = now()
+        where nm_id = $1 and worker_id = $2 and claim_generation = $3",
+            shard.ident()
+        ))
+        .bind(claim.nm_id)
+        .bind(&claim.worker_id)
+        .bind(claim.generation)
+        .execute(&self.client)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn fail_job(
+        &self,
+        shard: &Shard,
+        claim: &Claim,
+        failure: Failure,
+        backoff: &Backoff,
+    ) -> anyhow::Result<bool> {
+        let lost = self
+            .batch_fail_jobs(shard, vec![(claim.clone(), failure)], backoff)
+            .await?;
+        Ok(lost.is_empty())
+    }
+
+    async fn clear(&self, shard: &Shard) -> anyhow::Result<u64> {
+        let result = sqlx::query(&format!("delete from {}", shard.ident()))
             .execute(&self.client)
             .await?;
-        Ok(())
+        Ok(result.rows_affected())
+    }
+
+    async fn requeue(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64> {
+        self.update_where(
+            shard,
+            "in_process = false,
+            is_finished = false,
+            worker_id = null,
+            claimed_at = null,
+            lease_until = null,
+            next_attempt_at = null,
+            finished_at = null",
+            filter,
+        )
+        .await
+    }
+
+    async fn reset_retries(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64> {
+        self.update_where(
+            shard,
+            "retries = 0,
+            next_attempt_at = null,
+            error = '',
+            error_kind = null,
+            error_status = null,
+            error_url = null",
+            filter,
+        )
+        .await
+    }
+
+    async fn clear_results(&self, shard: &Shard, filter: &JobFilter) -> anyhow::Result<u64> {
+        self.update_where(
+            shard,
+            "new_pics_count = null, good_links = '', pics = '{}', pic_variants = '{}',
+            finished_at = null",
+            filter,
+        )
+        .await
     }
 
     async fn batch_finish_jobs(
         &self,
-        shard: &str,
-        nms: Vec<(i64, i16, String)>,
-    ) -> anyhow::Result<()> {
-        let query = generate_batch_finish_jobs(shard, nms);
-        debug!("batch_finish_jobs>>> {query}");
-        sqlx::query(&query).execute(&self.client).await?;
+        shard: &Shard,
+        nms: Vec<(Claim, i16, String, Vec<i16>, Variants)>,
+    ) -> anyhow::Result<Vec<Claim>> {
+        if nms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut tx = self.client.begin().await?;
+        let lost = finish_in(&mut tx, shard, &nms).await?;
+        tx.commit().await?;
+        Ok(lost)
+    }
+
+    async fn batch_fail_jobs(
+        &self,
+        shard: &Shard,
+        nms: Vec<(Claim, Failure)>,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>> {
+        if nms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut tx = self.client.begin().await?;
+        let (lost, buried) = fail_in(&mut tx, shard, &nms, backoff).await?;
+        tx.commit().await?;
+        if buried > 0 {
+            info!("{buried} nomenclatures of {shard} were moved to dead_letter");
+        }
+        Ok(lost)
+    }
+
+    async fn attempts(&self, shard: &Shard, nm_id: i64) -> anyhow::Result<Vec<Attempt>> {
+        let result: Vec<Attempt> = sqlx::query_as(
+            "select shard, nm_id, worker_id, started_at, finished_at, statuses, outcome, error
+            from attempts
+            where shard = $1 and nm_id = $2
+            order by started_at, id",
+        )
+        .bind(shard.as_str())
+        .bind(nm_id)
+        .fetch_all(&self.client)
+        .await?;
+        Ok(result)
+    }
+
+    async fn rechecks(&self, shard: &Shard, nm_id: i64) -> anyhow::Result<Vec<Recheck>> {
+        let result: Vec<Recheck> = sqlx::query_as(
+            "select shard, nm_id, checked_at, previous_pics_count, new_pics_count, good_links
+            from rechecks
+            where shard = $1 and nm_id = $2
+            order by checked_at, id",
+        )
+        .bind(shard.as_str())
+        .bind(nm_id)
+        .fetch_all(&self.client)
+        .await?;
+        Ok(result)
+    }
+
+    async fn commit_batch(
+        &self,
+        shard: &Shard,
+        batch: &Batch,
+        backoff: &Backoff,
+    ) -> anyhow::Result<Vec<Claim>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut attempt = 1;
+        loop {
+            let result: anyhow::Result<(Vec<Claim>, u64)> = async {
+                let mut tx = self.client.begin().await?;
+                let mut lost = finish_in(&mut tx, shard, &batch.finished).await?;
+                let (lost_failed, buried) = fail_in(&mut tx, shard, &batch.failed, backoff).await?;
+                lost.extend(lost_failed);
+                let lost = still_lost(&mut tx, shard, lost).await?;
+                attempts_in(&mut tx, shard, &batch.attempts).await?;
+                tx.commit().await?;
+                Ok((lost, buried))
+            }
+            .await;
+            match result {
+                Ok((lost, buried)) => {
+                    if buried > 0 {
+                        info!("{buried} nomenclatures of {shard} were moved to dead_letter");
+                    }
+                    return Ok(lost);
+                }
+                Err(err) if attempt < COMMIT_ATTEMPTS && is_transient(&err) => {
+                    warn!("commit_batch attempt {attempt} for {shard} failed: {err}");
+                    tokio::time::sleep(Duration::from_millis(200 << attempt)).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn dead_letters(
+        &self,
+        shard: &Shard,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<DeadLetter>> {
+        let result: Vec<DeadLetter> = sqlx::query_as(
+            "select * from dead_letter
+            where shard = $1
+            order by dead_at desc, nm_id
+            limit $2 offset $3",
+        )
+        .bind(shard.as_str())
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.client)
+        .await?;
+        Ok(result)
+    }
+
+    async fn requeue_dead_letters(&self, shard: &Shard, nm_ids: Vec<i64>) -> anyhow::Result<u64> {
+        let result = sqlx::query(&format!(
+            "with requeued as (
+                insert into {table} (nm_id, old_pics_count, failures)
+                select nm_id, old_pics_count, history from dead_letter
+                where shard = $1 and nm_id = any($2)
+                on conflict (nm_id) do nothing
+                returning nm_id
+            )
+            delete from dead_letter
+            where shard = $1 and nm_id in (select nm_id from requeued)",
+            table = shard.ident()
+        ))
+        .bind(shard.as_str())
+        .bind(nm_ids)
+        .execute(&self.client)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn purge_dead_letters(
+        &self,
+        shard: &Shard,
+        nm_ids: Option<Vec<i64>>,
+    ) -> anyhow::Result<u64> {
+        let result = match nm_ids {
+            Some(nm_ids) => {
+                sqlx::query("delete from dead_letter where shard = $1 and nm_id = any($2)")
+                    .bind(shard.as_str())
+                    .bind(nm_ids)
+                    .execute(&self.client)
+                    .await?
+            }
+            None => {
+                sqlx::query("delete from dead_letter where shard = $1")
+                    .bind(shard.as_str())
+                    .execute(&self.client)
+                    .await?
+            }
+        };
+        Ok(result.rows_affected())
+    }
+
+    async fn shards(&self) -> anyhow::Result<Vec<Shard>> {
+        let shards: Vec<(String,)> = sqlx::query_as(
+            "select s.name from shards s
+            join information_schema.tables t
+                on t.table_schema = current_schema() and t.table_name = s.name
+            order by length(s.name), s.name",
+        )
+        .fetch_all(&self.client)
+        .await?;
+        shards.iter().map(|(name,)| Shard::new(name)).collect()
+    }
+
+    async fn shard(&self, name: &str) -> anyhow::Result<Shard> {
+        let shard = Shard::new(name)?;
+        let (registered, exists): (bool, bool) = sqlx::query_as(
+            "select
+                exists(select 1 from shards where name = $1),
+                exists(
+                    select 1 from information_schema.tables
+                    where table_schema = current_schema() and table_name = $1
+                )",
+        )
+        .bind(shard.as_str())
+        .fetch_one(&self.client)
+        .await?;
+        if !registered {
+            anyhow::bail!("shard [{shard}] is not registered in shards");
+        }
+        if !exists {
+            anyhow::bail!("shard [{shard}] is registered but its table does not exist");
+        }
+        Ok(shard)
+    }
+
+    async fn create_shard(&self, shard: &Shard) -> anyhow::Result<()> {
+        sqlx::query("select create_shard($1)")
+            .bind(shard.as_str())
+            .execute(&self.client)
+            .await?;
         Ok(())
     }
 
-    async fn batch_fail_jobs(&self, shard: &str, nms: Vec<i64>) -> anyhow::Result<()> {
-        let query = generate_batch_fail_jobs(shard, nms);
-        // println!("batch_fail_jobs>>> {query}");
-        sqlx::query(&query).execute(&self.client).await?;
+    async fn wait_for_jobs(&self, shard: &Shard, timeout: Duration) -> anyhow::Result<bool> {
+        self.listen().await?;
+        Ok(self.jobs.wait(shard.as_str(), timeout).await)
+    }
+
+    async fn stats(&self, shard: &Shard) -> anyhow::Result<ShardStats> {
+        let result: ShardStats = sqlx::query_as(&format!(
+            "with s as (
+                select
+                    count(*) as total,
+                    count(*) filter (where not in_process and not is_finished and retries = 0) as pending,
+                    count(*) filter (where in_process) as in_process,
+                    count(*) filter (where is_finished) as finished,
+                    count(*) filter (where not in_process and not is_finished and retries > 0) as failed_retrying,
+                    count(*) filter (where is_finished and new_pics_count < old_pics_count) as regressed
+                from {table}
+            ), d as (
+                select count(*) as exhausted from dead_letter where shard = $1
+            )
+            select s.total + d.exhausted as total, s.pending, s.in_process, s.finished,
+                s.failed_retrying, d.exhausted, s.regressed
+            from s cross join d",
+            table = shard.ident()
+        ))
+        .bind(shard.as_str())
+        .fetch_one(&self.client)
+        .await?;
+        Ok(result)
+    }
+
+    async fn busiest_shard(&self, except: &[Shard]) -> anyhow::Result<Option<Shard>> {
+        let busiest: Option<(String,)> = sqlx::query_as(
+            "select n.shard from nomenclatures n
+            join shards s on s.name = n.shard
+            where n.shard <> all($2)
+                and n.in_process = false and n.is_finished = false and n.retries < $1
+                and (n.next_attempt_at is null or n.next_attempt_at <= now())
+            group by n.shard
+            order by count(*) desc
+            limit 1",
+        )
+        .bind(RETRIES)
+        .bind(except.iter().map(Shard::to_string).collect::<Vec<_>>())
+        .fetch_optional(&self.client)
+        .await?;
+        busiest.map(|(name,)| Shard::new(&name)).transpose()
+    }
+
+    async fn heartbeat(&self, worker: &Worker) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into workers
+                (worker_id, hostname, shards, version, started_at, heartbeat_at, finished, failed, lost_claims)
+            values ($1, $2, $3, $4, $5, now(), $6, $7, $8)
+            on conflict (worker_id) do update set
+                hostname = excluded.hostname,
+                shards = excluded.shards,
+                version = excluded.version,
+                started_at = excluded.started_at,
+                heartbeat_at = excluded.heartbeat_at,
+                finished = excluded.finished,
+                failed = excluded.failed,
+                lost_claims = excluded.lost_claims",
+        )
+        .bind(&worker.worker_id)
+        .bind(&worker.hostname)
+        .bind(&worker.shards)
+        .bind(&worker.version)
+        .bind(worker.started_at)
+        .bind(worker.finished)
+        .bind(worker.failed)
+        .bind(worker.lost_claims)
+        .execute(&self.client)
+        .await?;
         Ok(())
     }
+
+    async fn deregister_worker(&self, worker_id: &str) -> anyhow::Result<()> {
+        sqlx::query("delete from workers where worker_id = $1")
+            .bind(worker_id)
+            .execute(&self.client)
+            .await?;
+        Ok(())
+    }
+
+    async fn workers(&self) -> anyhow::Result<Vec<Worker>> {
+        let result: Vec<Worker> = sqlx::query_as("select * from workers order by worker_id")
+            .fetch_all(&self.client)
+            .await?;
+        Ok(result)
+    }
 }
 
-fn generate_batch_finish_jobs(shard: &str, nms: Vec<(i64, i16, String)>) -> String {
-    let mut values = String::from("(values ");
-    let mut delimiter = ',';
-    for (idx, (nm_id, new_pics_count, good_links)) in nms.iter().enumerate() {
-        if idx == nms.len() - 1 {
-            delimiter = ' ';
-        }
-        values
-            .push_str(format!("({nm_id}, '{good_links}', {new_pics_count}, false, true)").as_str());
-        values.push(delimiter);
+/// возвращает потерянные захваты
+async fn finish_in(
+    tx: &mut Transaction<'_, Postgres>,
+    shard: &Shard,
+    nms: &[(Claim, i16, String, Vec<i16>, Variants)],
+) -> anyhow::Result<Vec<Claim>> {
+    if nms.is_empty() {
+        return Ok(Vec::new());
     }
-    values.push(')');
-    let res = format!(
-        "update {shard} as n set
-    		in_process = c.in_process,
-    		is_finished = c.is_finished,
-            good_links = c.good_links,
-            new_pics_count = c.new_pics_count
-		from {values} as c(nm_id, good_links, new_pics_count, in_process, is_finished)
-		where c.nm_id = n.nm_id"
-    );
-    // println!("here {res}");
-    res
+    let mut nm_ids = Vec::with_capacity(nms.len());
+    let mut worker_ids = Vec::with_capacity(nms.len());
+    let mut generations = Vec::with_capacity(nms.len());
+    let mut new_pics_counts = Vec::with_capacity(nms.len());
+    let mut good_links = Vec::with_capacity(nms.len());
+    // массивы разной длины не собрать в int2[][], поэтому pics идут литералами '{1,2,4}'
+    let mut pics = Vec::with_capacity(nms.len());
+    let mut variants = Vec::with_capacity(nms.len());
+    for (claim, new_pics_count, links, found, pic_variants) in nms {
+        nm_ids.push(claim.nm_id);
+        worker_ids.push(claim.worker_id.as_str());
+        generations.push(claim.generation);
+        new_pics_counts.push(*new_pics_count);
+        good_links.push(links.as_str());
+        pics.push(format!(
+            "{{{}}}",
+            found
+                .iter()
+                .map(i16::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+        variants.push(serde_json::to_string(pic_variants)?);
+    }
+    let query = batch_finish_jobs_query(shard);
+    debug!("batch_finish_jobs>>> {} rows", nm_ids.len());
+    let applied: Vec<i64> = sqlx::query_scalar(&query)
+        .bind(nm_ids)
+        .bind(worker_ids)
+        .bind(generations)
+        .bind(new_pics_counts)
+        .bind(good_links)
+        .bind(pics)
+        .bind(variants)
+        .bind(shard.as_str())
+        .fetch_all(&mut *tx)
+        .await?;
+    Ok(lost_claims(nms.iter().map(|(claim, ..)| claim), &applied))
 }
 
-fn generate_batch_fail_jobs(shard: &str, nms: Vec<i64>) -> String {
-    let mut values = String::from("(values ");
-    let mut delimiter = ',';
-    for (idx, nm_id) in nms.iter().enumerate() {
-        if idx == nms.len() - 1 {
-            delimiter = ' ';
-        }
-        values.push_str(format!("({nm_id}, false, true)").as_str());
-        values.push(delimiter);
+/// возвращает потерянные захваты и количество джоб, перенесенных в dead_letter
+async fn fail_in(
+    tx: &mut Transaction<'_, Postgres>,
+    shard: &Shard,
+    nms: &[(Claim, Failure)],
+    backoff: &Backoff,
+) -> anyhow::Result<(Vec<Claim>, u64)> {
+    if nms.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    let mut nm_ids = Vec::with_capacity(nms.len());
+    let mut worker_ids = Vec::with_capacity(nms.len());
+    let mut generations = Vec::with_capacity(nms.len());
+    let mut messages = Vec::with_capacity(nms.len());
+    let mut kinds = Vec::with_capacity(nms.len());
+    let mut statuses = Vec::with_capacity(nms.len());
+    let mut urls = Vec::with_capacity(nms.len());
+    for (claim, failure) in nms {
+        nm_ids.push(claim.nm_id);
+        worker_ids.push(claim.worker_id.as_str());
+        generations.push(claim.generation);
+        messages.push(failure.message.as_str());
+        kinds.push(failure.kind.as_str());
+        statuses.push(failure.status);
+        urls.push(failure.url.as_deref());
+    }
+    let applied: Vec<i64> = sqlx::query_scalar(&batch_fail_jobs_query(shard))
+        .bind(nm_ids)
+        .bind(worker_ids)
+        .bind(generations)
+        .bind(messages)
+        .bind(kinds)
+        .bind(statuses)
+        .bind(urls)
+        .bind(backoff.base.as_secs_f64())
+        .bind(backoff.multiplier)
+        .bind(backoff.jitter)
+        .bind(backoff.cap.as_secs_f64())
+        .fetch_all(&mut *tx)
+        .await?;
+    // перепроверка, исчерпавшая ретраи, не должна стереть прошлый результат:
+    // номенклатура снова готова и перепроверится после backoff последней попытки
+    sqlx::query(&format!(
+        "update {} set is_finished = true, retries = 0
+        where nm_id = any($1) and retries >= $2 and finished_at is not null",
+        shard.ident()
+    ))
+    .bind(&applied)
+    .bind(RETRIES)
+    .execute(&mut *tx)
+    .await?;
+    let buried = sqlx::query(&bury_exhausted_query(shard))
+        .bind(&applied)
+        .bind(RETRIES)
+        .bind(shard.as_str())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    Ok((
+        lost_claims(nms.iter().map(|(claim, _)| claim), &applied),
+        buried,
+    ))
+}
+
+fn lost_claims<'a>(claims: impl Iterator<Item = &'a Claim>, applied: &[i64]) -> Vec<Claim> {
+    claims
+        .filter(|claim| !applied.contains(&claim.nm_id))
+        .cloned()
+        .collect()
+}
+
+/// отбрасывает захваты, результат которых уже записан этим воркером: повтор транзакции
+/// или пачки из spill после COMMIT, который прошел, но ответ на него не дошел;
+/// такая строка сохранила worker_id, а claim_generation увеличил наш finish/fail
+async fn still_lost(
+    tx: &mut Transaction<'_, Postgres>,
+    shard: &Shard,
+    lost: Vec<Claim>,
+) -> anyhow::Result<Vec<Claim>> {
+    if lost.is_empty() {
+        return Ok(lost);
     }
-    values.push(')');
+    let applied: Vec<i64> = sqlx::query_scalar(&format!(
+        "select c.nm_id
+        from unnest($1::int8[], $2::text[], $3::int8[]) as c(nm_id, worker_id, claim_generation)
+        where exists (
+            select 1 from {} as n
+            where n.nm_id = c.nm_id and n.in_process = false
+                and n.worker_id = c.worker_id and n.claim_generation = c.claim_generation + 1
+        ) or exists (
+            select 1 from dead_letter as d
+            where d.shard = $4 and d.nm_id = c.nm_id and d.worker_id = c.worker_id
+        )",
+        shard.ident()
+    ))
+    .bind(lost.iter().map(|claim| claim.nm_id).collect::<Vec<_>>())
+    .bind(
+        lost.iter()
+            .map(|claim| claim.worker_id.as_str())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        lost.iter()
+            .map(|claim| claim.generation)
+            .collect::<Vec<_>>(),
+    )
+    .bind(shard.as_str())
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(lost_claims(lost.iter(), &applied))
+}
+
+async fn attempts_in(
+    tx: &mut Transaction<'_, Postgres>,
+    shard: &Shard,
+    attempts: &[Attempt],
+) -> anyhow::Result<()> {
+    if attempts.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        "insert into attempts
+            (shard, nm_id, worker_id, started_at, finished_at, statuses, outcome, error)
+        select $1, a.nm_id, a.worker_id, a.started_at, a.finished_at, a.statuses, a.outcome, a.error
+        from jsonb_to_recordset($2) as a(
+            nm_id int8,
+            worker_id text,
+            started_at timestamptz,
+            finished_at timestamptz,
+            statuses int2[],
+            outcome text,
+            error text
+        )
+        on conflict (shard, nm_id, worker_id, started_at) do nothing",
+    )
+    .bind(shard.as_str())
+    .bind(serde_json::to_value(attempts)?)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// ошибки, после которых транзакцию имеет смысл повторить: обрыв соединения,
+/// исчерпанный пул, конфликты сериализации и рестарт сервера
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed) => true,
+        Some(sqlx::Error::Database(err)) => err.code().is_some_and(|code| {
+            code.starts_with("08") || matches!(&*code, "40001" | "40P01" | "57P01" | "53300")
+        }),
+        _ => false,
+    }
+}
+
+/// условие на строки шарда, $1 - массив nm_id для JobFilter::NmIds
+fn job_filter_sql(filter: &JobFilter) -> &'static str {
+    match filter {
+        JobFilter::Stuck => "in_process",
+        JobFilter::ZeroPics => "is_finished and new_pics_count = 0",
+        JobFilter::Failed => "not is_finished and retries > 0",
+        JobFilter::NmIds(_) => "nm_id = any($1)",
+        JobFilter::Regressed => "is_finished and new_pics_count < old_pics_count",
+        JobFilter::Gaps => "is_finished and cardinality(pics) > coalesce(new_pics_count, 0)",
+    }
+}
+
+/// будит воркеров шарда, для вставок это делает триггер notify_shard_jobs
+async fn notify_jobs(client: &Pool<Postgres>, shard: &Shard) -> anyhow::Result<()> {
+    sqlx::query("select pg_notify($1, $2)")
+        .bind(JOBS_CHANNEL)
+        .bind(shard.as_str())
+        .execute(client)
+        .await?;
+    Ok(())
+}
+
+/// текст запроса зависит только от шарда, поэтому sqlx кеширует подготовленный
+/// statement и переиспользует его для каждого батча; результат перепроверки
+/// вместе с предыдущим new_pics_count дописывается в rechecks
+fn batch_finish_jobs_query(shard: &Shard) -> String {
     format!(
-        "update {shard} as n set
-			in_process = c.in_process,
-			retries = retries + 1
-		from {values} as c(nm_id, in_process)
-		where c.nm_id = n.nm_id"
+        "with previous as (
+            select nm_id, new_pics_count from {table} where nm_id = any($1)
+        ), done as (
+        update {table} as n set
+            in_process = false,
+            lease_until = null,
+            next_attempt_at = null,
+            is_finished = true,
+            error = '',
+            error_kind = null,
+            error_status = null,
+            error_url = null,
+            claim_generation = n.claim_generation + 1,
+            good_links = c.good_links,
+            new_pics_count = c.new_pics_count,
+            pics = c.pics::int2[],
+            pic_variants = c.pic_variants::jsonb,
+            finished_at = now()
+        from unnest($1::int8[], $2::text[], $3::int8[], $4::int2[], $5::text[], $6::text[], $7::text[])
+            as c(nm_id, worker_id, claim_generation, new_pics_count, good_links, pics, pic_variants)
+        where c.nm_id = n.nm_id
+            and n.worker_id = c.worker_id and n.claim_generation = c.claim_generation
+        returning n.nm_id, n.rechecks, n.new_pics_count, n.good_links
+        ), logged as (
+            insert into rechecks (shard, nm_id, previous_pics_count, new_pics_count, good_links)
+            select $8, done.nm_id, previous.new_pics_count, done.new_pics_count, done.good_links
+            from done join previous using (nm_id)
+            where done.rechecks > 0
+        )
+        select nm_id from done",
+        table = shard.ident()
+    )
+}
+
+fn batch_fail_jobs_query(shard: &Shard) -> String {
+    format!(
+        "update {table} as n set
+            in_process = false,
+            lease_until = null,
+            retries = n.retries + 1,
+            claim_generation = n.claim_generation + 1,
+            next_attempt_at = {},
+            error = c.error,
+            error_kind = c.error_kind,
+            error_status = c.error_status,
+            error_url = c.error_url,
+            failures = n.failures || jsonb_build_array(jsonb_build_object(
+                'at', now(),
+                'worker_id', n.worker_id,
+                'kind', c.error_kind,
+                'status', c.error_status,
+                'url', c.error_url,
+                'error', c.error
+            ))
+        from unnest($1::int8[], $2::text[], $3::int8[], $4::text[], $5::text[], $6::int2[], $7::text[])
+            as c(nm_id, worker_id, claim_generation, error, error_kind, error_status, error_url)
+        where c.nm_id = n.nm_id
+            and n.worker_id = c.worker_id and n.claim_generation = c.claim_generation
+        returning n.nm_id",
+        next_attempt_at(8),
+        table = shard.ident()
+    )
+}
+
+/// переносит исчерпавшие ретраи джобы в dead_letter вместе с историей ошибок
+fn bury_exhausted_query(shard: &Shard) -> String {
+    format!(
+        "with dead as (
+            delete from {table}
+            where nm_id = any($1) and retries >= $2 and finished_at is null
+            returning *
+        )
+        insert into dead_letter
+            (shard, nm_id, old_pics_count, retries, error, error_kind, error_status, error_url, history, worker_id)
+        select $3, nm_id, old_pics_count, retries, error, error_kind, error_status, error_url, failures, worker_id
+        from dead
+        on conflict (shard, nm_id) do update set
+            old_pics_count = excluded.old_pics_count,
+            retries = excluded.retries,
+            error = excluded.error,
+            error_kind = excluded.error_kind,
+            error_status = excluded.error_status,
+            error_url = excluded.error_url,
+            history = excluded.history,
+            worker_id = excluded.worker_id,
+            dead_at = now()",
+        table = shard.ident()
+    )
+}
+
+/// момент следующей попытки по Backoff, считается прямо в запросе, чтобы батч
+/// оставался одним statement'ом; параметры начиная с ${first}: base, multiplier, jitter, cap
+fn next_attempt_at(first: usize) -> String {
+    let (base, multiplier, jitter, cap) = (first, first + 1, first + 2, first + 3);
+    format!(
+        "now() + make_interval(secs => least(${cap}::float8, ${base}::float8 * power(${multiplier}::float8, n.retries::float8)) * (1 + ${jitter}::float8 * (2 * random() - 1)))"
     )
 }
 
@@ -200,3 +1122,90 @@ pub fn build_config(
 ) -> String {
     format!("postgres://{user}:{password}@{host}:{port}/{dbname}?sslmode=disable")
 }
+
+/// пул к базе из TEST_POSTGRES_DB с миграциями; без переменной тесты с postgres пропускаются
+#[cfg(test)]
+async fn test_pool() -> Option<SqlxPool> {
+    let database = std::env::var("TEST_POSTGRES_DB").ok()?;
+    let var = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.into());
+    let pool = SqlxPool::new(
+        var("POSTGRES_HOST", "localhost"),
+        var("POSTGRES_PORT", "5433"),
+        var("POSTGRES_USER", "content"),
+        var("POSTGRES_PASSWORD", "1231"),
+        database,
+    )
+    .await
+    .unwrap();
+    pool.migrate().await.unwrap();
+    Some(pool)
+}
+
+#[tokio::test]
+async fn test_reset_results_exhaust_to_dead_letter() {
+    // после requeue и clear_results прежнего результата нет, упавшая джоба хоронится
+    let Some(queue) = test_pool().await else {
+        return;
+    };
+    let lease = Duration::from_secs(60);
+    let recheck = RecheckPolicy::default();
+    let no_backoff = Backoff {
+        base: Duration::ZERO,
+        multiplier: 1.0,
+        jitter: 0.0,
+        cap: Duration::ZERO,
+    };
+    for (name, requeue) in [("test_requeue", true), ("test_clear_results", false)] {
+        let shard = Shard::new(name).unwrap();
+        queue.create_shard(&shard).await.unwrap();
+        queue.clear(&shard).await.unwrap();
+        queue.purge_dead_letters(&shard, None).await.unwrap();
+        sqlx::query(&format!(
+            "insert into {} (nm_id, old_pics_count) values (1, 3)",
+            shard.ident()
+        ))
+        .execute(&queue.client)
+        .await
+        .unwrap();
+
+        let nms = queue.pull(&shard, "w1", 1, lease, &recheck).await.unwrap();
+        queue
+            .batch_finish_jobs(
+                &shard,
+                vec![(
+                    nms[0].claim(),
+                    3,
+                    "a;b;c".to_string(),
+                    vec![1, 2, 3],
+                    Variants::new(),
+                )],
+            )
+            .await
+            .unwrap();
+        let filter = JobFilter::NmIds(vec![1]);
+        if requeue {
+            queue.requeue(&shard, &filter)
+        } else {
+            queue.clear_results(&shard, &filter)
+        }
+        .await
+        .unwrap();
+
+        for _ in 0..RETRIES {
+            let nms = queue.pull(&shard, "w1", 1, lease, &recheck).await.unwrap();
+            assert_eq!(1, nms.len());
+            let failure = Failure {
+                kind: crate::store::models::FailureKind::Status,
+                status: Some(503),
+                url: None,
+                message: "unexpected response.status".to_string(),
+            };
+            queue
+                .batch_fail_jobs(&shard, vec![(nms[0].claim(), failure)], &no_backoff)
+                .await
+                .unwrap();
+        }
+        let dead = queue.dead_letters(&shard, 10, 0).await.unwrap();
+        assert_eq!(vec![1], dead.iter().map(|d| d.nm_id).collect::<Vec<_>>());
+    }
+}